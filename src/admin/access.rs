@@ -0,0 +1,41 @@
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, http::header::AUTHORIZATION, web, FromRequest, HttpRequest};
+use subtle::ConstantTimeEq;
+
+use crate::{auth::errors::AuthError, config::server_config::ServerConfig};
+
+
+///Extractor for administrative handlers.
+///Requires `Authorization: Bearer <admin.token>`. Without a configured token every request is refused.
+pub struct AdminAccess;
+
+impl FromRequest for AdminAccess{
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        return ready(authorize(req))
+    }
+}
+
+fn authorize(req: &HttpRequest) -> Result<AdminAccess, AuthError>{
+    let token = req.app_data::<web::Data<ServerConfig>>()
+        .and_then(|config| config.admin.token.clone());
+
+    let expected = match token{
+        Some(token) => token,
+        None => return Err(AuthError::AdminDisabled),
+    };
+
+    let presented = req.headers().get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if bool::from(presented.as_bytes().ct_eq(expected.as_bytes())){
+        return Ok(AdminAccess)
+    }
+
+    return Err(AuthError::InvalidAdminToken)
+}
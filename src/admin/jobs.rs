@@ -0,0 +1,67 @@
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::{auth::errors::AuthError, database::store::{Backend, MaintenanceStore}, maintenance::maintainer::{Maintainer, Trigger, TriggerError}};
+
+use super::access::AdminAccess;
+
+const DEFAULT_HISTORY: usize = 20;
+const MAX_HISTORY: usize = 200;
+
+
+#[derive(Deserialize)]
+pub struct HistoryQuery{
+    limit: Option<usize>
+}
+
+///Handler that lists every maintenance job with its last run.
+pub async fn list_jobs<B: Backend>(_admin: AdminAccess, maintainer: web::Data<Maintainer<B>>) -> impl Responder {
+    return HttpResponse::Ok()
+    .status(StatusCode::OK)
+    .json(maintainer.status())
+}
+
+///Handler that returns the recorded runs of a job, newest first.
+pub async fn job_history<B: Backend>(
+    _admin: AdminAccess,
+    maintainer: web::Data<Maintainer<B>>,
+    database: web::Data<B>,
+    name: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, AuthError> {
+    if !maintainer.has_job(&name){
+        return Err(AuthError::UnknownJob)
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY).min(MAX_HISTORY);
+    let name = name.into_inner();
+    let runs = database.run(move |database_handler| Ok(database_handler.get_maintenance_runs(&name, limit)?)).await;
+
+    match runs{
+        Ok(runs) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(runs))
+        },
+        Err(error) => {
+            println!("Error while fetching maintenance runs: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
+
+///Handler that starts a job in the background. Its outcome is recorded in the job history.
+pub async fn trigger_job<B: Backend>(_admin: AdminAccess, maintainer: web::Data<Maintainer<B>>, name: web::Path<String>) -> Result<HttpResponse, AuthError> {
+    match maintainer.spawn(&name, Trigger::Manual){
+        Ok(()) => {
+            return Ok(HttpResponse::Accepted()
+            .json("Status : Job started."))
+        },
+        Err(TriggerError::UnknownJob) => {
+            return Err(AuthError::UnknownJob)
+        },
+        Err(TriggerError::AlreadyRunning) => {
+            return Err(AuthError::JobRunning)
+        },
+    }
+}
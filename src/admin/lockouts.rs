@@ -0,0 +1,73 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use serde::Deserialize;
+
+use crate::{auth::{errors::AuthError, lockout::{find_account, ACCOUNT, IP}, usernames::{legacy_index, UsernameKeys}}, database::store::{Backend, LoginAttemptStore}, utils::time::unix_now};
+
+use super::access::AdminAccess;
+
+
+///Account or address to unlock. Accounts are named by username.
+#[derive(Deserialize)]
+pub struct UnlockBody{
+    username: Option<String>,
+    ip: Option<String>,
+}
+
+///Handler that lists the accounts and addresses currently locked.
+pub async fn list_lockouts<B: Backend>(_admin: AdminAccess, database: web::Data<B>) -> Result<HttpResponse, AuthError> {
+    let locks = database.run(|database_handler| Ok(database_handler.get_login_locks(unix_now())?)).await;
+
+    match locks{
+        Ok(locks) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(locks))
+        },
+        Err(error) => {
+            println!("Error while fetching lockouts: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
+
+///Handler that clears the failed logins of an account and/or address, lifting any lock.
+pub async fn unlock<B: Backend>(_admin: AdminAccess, database: web::Data<B>, keys: web::Data<UsernameKeys>, body: web::Json<UnlockBody>) -> Result<HttpResponse, AuthError> {
+    let username = body.username.as_ref().map(|username| (keys.index(username), legacy_index(username)));
+    let ip = body.ip.as_ref().map(|ip| ip.trim().to_string());
+
+    if username.is_none() && ip.is_none(){
+        return Err(AuthError::MissingUnlockTarget)
+    }
+
+    let cleared = database.run(move |database_handler| {
+        let mut targets: Vec<(&'static str, String)> = vec![];
+        let mut cleared = 0;
+
+        //The account is counted under the key login resolves, legacy accounts under their legacy index
+        if let Some((hashed_username, legacy_username)) = username{
+            let (account, _) = find_account(database_handler, hashed_username, legacy_username)?;
+            targets.push((ACCOUNT, account));
+        }
+        if let Some(ip) = ip{
+            targets.push((IP, ip));
+        }
+
+        for (scope, key) in targets{
+            cleared += database_handler.clear_login_attempts(scope, &key)?;
+        }
+
+        return Ok(cleared)
+    }).await;
+
+    match cleared{
+        Ok(cleared) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(format!("Status : Cleared {} counter(s).", cleared)))
+        },
+        Err(error) => {
+            println!("Error while clearing login attempts: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
\ No newline at end of file
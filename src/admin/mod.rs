@@ -0,0 +1,4 @@
+pub mod access;
+pub mod jobs;
+pub mod lockouts;
+pub mod users;
\ No newline at end of file
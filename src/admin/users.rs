@@ -0,0 +1,46 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use serde::Serialize;
+
+use crate::{auth::{errors::AuthError, usernames::{is_legacy_index, UsernameKeys}}, database::store::{Backend, UserStore}};
+
+use super::access::AdminAccess;
+
+
+///Registered user as shown to administrators. The username is only known when stored for display.
+#[derive(Serialize)]
+pub struct UserSummary{
+    id: String,
+    username: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
+    ///Still stored under the unkeyed SHA-256 index, migrated on the next login.
+    legacy_index: bool,
+    ///Password hash to be replaced at the next login.
+    rehash_required: bool,
+}
+
+///Handler that lists every registered user.
+pub async fn list_users<B: Backend>(_admin: AdminAccess, database: web::Data<B>, keys: web::Data<UsernameKeys>) -> Result<HttpResponse, AuthError> {
+    let users = database.run(|database_handler| Ok(database_handler.list_users()?)).await;
+
+    match users{
+        Ok(users) => {
+            let summaries: Vec<UserSummary> = users.iter().map(|user| UserSummary {
+                id: user.get_id().to_string(),
+                username: user.get_display_username().as_deref().and_then(|sealed| keys.open(user.get_id(), sealed)),
+                email: user.get_email().clone(),
+                email_verified: user.is_email_verified(),
+                legacy_index: is_legacy_index(user.get_username()),
+                rehash_required: user.is_rehash_required()
+            }).collect();
+
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(summaries))
+        },
+        Err(error) => {
+            println!("Error while fetching users: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
@@ -1,276 +1,559 @@
-use std::str::FromStr;
-
 use actix_session::Session;
-use actix_web::{http::StatusCode, web, HttpResponse, HttpResponseBuilder, Responder};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{database::handler::DatabaseHandler, models::{database_models::User, server_models::MessageBody}};
+use crate::{config::server_config::{PasswordPolicy, ServerConfig}, database::store::{Backend, GuestStore, SessionStore, Store, UserStore}, models::database_models::{Session as DbSession, User}, utils::{client_ip::TrustedProxies, payload::CredentialsPayload, time::unix_now}};
+
+use super::{errors::{conflict, AuthError}, hasher::{hash_new_password, run_hasher}, lockout::{find_account, record_failed_login, Lockout}, pepper::Peppers, sessions::{cookie_ids, SessionCheck, SessionManager}, usernames::{legacy_index, UsernameKeys}, verification::{issue_email_verification, normalize_email}};
+
+
+///Outcome of the checks made before a password is hashed.
+enum Precheck<T>{
+    Passed(T),
+    Refused(AuthError),
+}
+
+///Guest session handed out by the guest handler.
+enum GuestSession{
+    Renewed(DbSession),
+    Created(DbSession),
+}
 
-use super::{hasher::Hasher, sessions::SessionManager};
 
 ///Handler that verifies credentials.
 ///Creates a new session and sends cookie to client side.
-pub async fn verify_credentials(session: Session, body: web::Json<MessageBody>) -> impl Responder {
-    match DatabaseHandler::new(){
-        Ok(database_handler) => {
-            let username = &body.data.username;
-            let password = &body.data.password;
-
-            let hasher = Hasher::new();
-            let hashed_username = hasher.hash_username(&username);
-
-            let mut matching_user: Vec<User> = vec![];
-
-            //users that have matching username
-            match database_handler.get_users(&hashed_username){
-                Ok(users_total) => {
-                    //for each user retrieve password and salt
-                    users_total.iter().for_each(|user| {
-                        let user_password = user.get_password();
-                        let user_salt = user.get_salt();
-
-                        //compute password given with salt found from db
-                        let computed_password = hasher.hash_password(password, &user_salt);
-
-                        //password computed and password provided match
-                        if computed_password.is_ok_and(|passwd| passwd.eq(user_password)){
-                            matching_user.push(user.clone())
-                        }
-                    });
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_credentials<B: Backend>(req: HttpRequest, session: Session, config: web::Data<ServerConfig>, database: web::Data<B>, keys: web::Data<UsernameKeys>, peppers: web::Data<Peppers>, proxies: web::Data<TrustedProxies>, body: CredentialsPayload) -> Result<HttpResponse, AuthError> {
+    let username = &body.data.username;
+    let password = &body.data.password;
+
+    let hashed_username = keys.index(username);
+    let client_ip = proxies.client_ip(req.peer_addr(), req.headers()).map(|address| address.to_string());
+
+    //Refuse throttled or locked attempts before spending a hash on them
+    let lookup = database.run({
+        let config = config.clone();
+        let hashed_username = hashed_username.clone();
+        let client_ip = client_ip.clone();
+        let legacy_username = legacy_index(username);
+
+        move |database_handler| {
+            let (account, users) = find_account(database_handler, hashed_username, legacy_username)?;
+            let lockout = Lockout::new(&config.lockout, &account, client_ip.as_deref());
+
+            if let Some(block) = lockout.check(database_handler)?{
+                return Ok(Err(block))
+            }
+
+            return Ok(Ok((account, users)))
+        }
+    }).await;
+
+    let (account, users_total) = match lookup{
+        Ok(Ok(lookup)) => lookup,
+        Ok(Err(block)) => return Err(AuthError::from(block)),
+        Err(error) => {
+            println!("Error while fetching users: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    };
+
+    //for each user verify the password against the stored PHC string
+    let mut matching_user: Vec<User> = run_hasher(&config.argon2, &peppers, {
+        let password = password.clone();
+
+        move |hasher| users_total.into_iter()
+            .filter(|user| hasher.verify_password(&password, user.get_password()))
+            .collect()
+    }).await?;
+
+    //Exactly one user may match, usernames are unique
+    let user = match matching_user.pop(){
+        Some(user) if matching_user.is_empty() => user,
+        Some(_) => {
+            println!("More than one user matched.");
+            return Err(AuthError::Internal("more than one user matched"))
+        },
+        None => {
+            let locked = database.run({
+                let config = config.clone();
+
+                move |database_handler| Ok(record_failed_login(database_handler, &config, &account, client_ip.as_deref())?)
+            }).await;
+
+            match locked{
+                Ok(true) => return Err(AuthError::Locked(config.lockout.lockout_seconds())),
+                Ok(false) => {},
+                Err(error) => println!("Error while recording failed login: {:?}", error),
+            }
+
+            return Err(AuthError::InvalidCredentials)
+        },
+    };
+
+    let manager = SessionManager::new(&config.session);
+
+    //Replace hashes made with outdated settings or flagged by maintenance while the password is at hand
+    let rehashed = run_hasher(&config.argon2, &peppers, {
+        let password = password.clone();
+        let rehash_required = user.is_rehash_required();
+        let stored = user.get_password().clone();
+
+        move |hasher| {
+            if !rehash_required && !hasher.needs_rehash(&stored){
+                return None
+            }
+
+            let salt = hasher.random_salt();
+
+            match hasher.hash_password(&password, &salt){
+                Ok(hash) => return Some((hash, salt)),
+                Err(error) => {
+                    println!("Error while rehashing password: {:?}", error);
+                    return None
+                },
+            }
+        }
+    }).await.unwrap_or(None);
+
+    //Move legacy rows to the blind index, and store the display name once it is enabled
+    let display_missing = user.get_display_username().is_none() && config.username.store_display;
+    let migration = match user.get_username().ne(&hashed_username) || display_missing{
+        true => Some(keys.display(user.get_id(), username)),
+        false => None,
+    };
+
+    let updated = database.run({
+        let config = config.clone();
+        let user = user.clone();
+
+        move |database_handler| {
+            let lockout = Lockout::new(&config.lockout, &account, client_ip.as_deref());
+
+            if let Err(error) = lockout.record_success(database_handler){
+                println!("Error while clearing failed logins: {:?}", error);
+            }
+
+            if let Some((hash, salt)) = rehashed{
+                match database_handler.rehash_password(user.get_id(), user.get_password(), &hash, &salt){
+                    Ok(rows) => println!("Rehashed password of user {:?}: {:?}", user.get_id(), rows),
+                    Err(error) => println!("Error while rehashing password: {:?}", error),
+                }
+            }
+
+            if let Some(display_username) = migration{
+                match database_handler.migrate_username(user.get_id(), user.get_username(), &hashed_username, display_username.as_deref()){
+                    Ok(rows) => println!("Migrated username of user {:?}: {:?}", user.get_id(), rows),
+                    Err(error) => println!("Error while migrating username: {:?}", error),
+                }
+            }
+
+            return Ok(())
+        }
+    }).await;
+
+    if let Err(error) = updated{
+        println!("Error while updating user after login: {:?}", error);
+    }
+
+    if config.email.require_verification && !user.is_email_verified(){
+        return Err(AuthError::EmailNotVerified)
+    }
+
+    //Existing session in request is renewed while valid, replaced once expired.
+    let mut cookie = cookie_ids(&session);
+
+    if let Some((session_id, user_id)) = cookie{
+        println!("Session name: {:?}", session_id);
+        println!("Session value: {:?}", user_id);
+
+        //check if session value (user id) matched users id from database.
+        //A guest logging in leaves its guest session behind, stale sessions are ignored,
+        //only another user's live session has to be logged out first
+        if !user.get_id().eq(&user_id){
+            let live = database.run(move |database_handler| {
+                if database_handler.delete_guest(&user_id, &session_id)? == 1{
+                    println!("Dropped guest {:?} on login.", user_id);
+                    return Ok(false)
+                }
+
+                return Ok(matches!(manager.check(database_handler, &session_id, &user_id)?, SessionCheck::Valid(_)))
+            }).await;
+
+            match live{
+                Ok(true) => return Err(AuthError::SessionMismatch),
+                Ok(false) => {
+                    //Not purge, which would also drop the cookie of the session created below
+                    session.clear();
+                    cookie = None;
                 },
                 Err(error) => {
-                    println!("Error while fetching users: {:?}", error);
-                    return HttpResponse::InternalServerError()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .json("Status : Database error. No such user.")
+                    println!("Error while checking session: {:?}", error);
+                    return Err(AuthError::Database(error))
                 },
-            };
+            }
+        }
+    }
 
-            match matching_user.len(){
-                1 => {
-                    let user = matching_user.pop().unwrap();
-                    let manager = SessionManager::new();
-
-                    match session.get::<String>("value"){
-                        Ok(value) => {
-                            match value{
-                                //Found existing session in request
-                                Some(session_value) => {
-                                    let session_name = session.get::<String>("name").unwrap();
-                                    println!("Session name: {:?}", session_name);
-                                    println!("Session value: {:?}", session_value);
-                                    
-                                    //check if session value (user id) matched users id from database
-                                    if !user.get_id().eq(&Uuid::from_str(session_value.as_str()).unwrap()){
-                                        let response = HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR)
-                                        .json("Status : Error during session validation.");
-
-                                        return response
-                                    }
-
-                                    let db_session = database_handler.get_session_from_id(&Uuid::from_str(session_name.unwrap().as_str()).unwrap());
-                                    println!("Found session in database: {:?}", db_session);
-                                    
-                                    //If session in database, renew session.
-                                    if db_session.is_ok_and(|x| x.is_some()){
-                                        session.renew();
-                                    }
-                                },
-                                //First session assignment for user.
-                                None => {
-                                    let name_ins_status = session.insert("name", Uuid::new_v4().to_string());
-                                    let val_ins_status = session.insert("value", user.get_id().to_string());
-    
-                                    if !name_ins_status.is_ok() && !val_ins_status.is_ok(){
-                                        let response = HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR)
-                                        .json("Status : Error during session creation.");
-                                        
-                                        return response
-                                    }
-
-                                    //check if generated id exists in database
-                                    loop{
-                                        let created_session = &manager.create_session(user.get_id());
-
-                                        //Save session to database
-                                        if database_handler.id_exists(&String::from("session"), &created_session.get_id()).is_ok_and(|x| x == false){
-                                            
-                                            //if not exists insert
-                                            match database_handler.insert_session(created_session){
-                                                Ok(rows) => {
-                                                    println!("Inserted session: {:?}", rows);
-                                                    break;
-                                                },
-                                                Err(error) => {
-                                                    println!("Error while inserting session to database: {:?}", error);
-                                                    return HttpResponse::InternalServerError()
-                                                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                                    .json("Status : Database error.")
-                                                },
-                                            }
-                                        }
-                                    }
-
-                                },
-                            }
-                        },
-                        Err(error) => {
-                            println!("Error: {:?}", error)
-                        },
-                    }
-
-                    let response = HttpResponseBuilder::new(StatusCode::ACCEPTED)
-                    .json("Status : User validated.");
-
-                    return response
+    let user_id = *user.get_id();
+    let created = database.run(move |database_handler| {
+        if let Some((session_id, user_id)) = cookie{
+            match manager.check(database_handler, &session_id, &user_id)?{
+                SessionCheck::Valid(db_session) => {
+                    println!("Found session in database: {:?}", db_session);
+                    return Ok(None)
                 },
-                _ => {
-                    println!("Less or more than one users matched.");
-                    return HttpResponse::InternalServerError()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .json("Status : Database error.")
-                }
+                SessionCheck::Expired => println!("Session expired, creating new session."),
+                SessionCheck::Missing => {},
+            }
+        }
+
+        //First session assignment for user, or a replacement. Check if generated id exists in database
+        loop{
+            let created_session = manager.create_session(&user_id);
+
+            if !database_handler.id_exists("session", created_session.get_id())?{
+                let rows = database_handler.insert_session(&created_session)?;
+                println!("Inserted session: {:?}", rows);
+
+                return Ok(Some(created_session))
+            }
+        }
+    }).await;
+
+    match created{
+        Ok(Some(created_session)) => {
+            //Cookie name is the database session id, so the session can be revoked later
+            let inserted = session.insert("name", created_session.get_id().to_string())
+                .and_then(|_| session.insert("value", user_id.to_string()));
+
+            if let Err(error) = inserted{
+                return Err(AuthError::Session(error.to_string()))
             }
-            
         },
+        Ok(None) => {},
         Err(error) => {
-            //HTTP ERROR response
-            println!("Error while fetching database: {:?}", error);
-            return HttpResponse::InternalServerError()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .json("Status : Database error. Initialization failed.")
-        }
+            println!("Error while creating session: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
     }
+
+    //Rotate the cookie on every login
+    session.renew();
+
+    return Ok(HttpResponse::Ok()
+    .status(StatusCode::OK)
+    .json("Status : User validated."))
 }
 
 
 ///Handler that saves credentials to database.
-pub async fn save_credentials(credentials: web::Json<MessageBody>) -> impl Responder {
-    let username = &credentials.data.username;
+pub async fn save_credentials<B: Backend>(config: web::Data<ServerConfig>, database: web::Data<B>, keys: web::Data<UsernameKeys>, peppers: web::Data<Peppers>, credentials: CredentialsPayload) -> Result<HttpResponse, AuthError> {
+    let username = credentials.data.username.clone();
     let password = &credentials.data.password;
 
-    if sanitize(password){
-        match DatabaseHandler::new(){
-            Ok(database_handler) => {
-                let mut hasher = Hasher::new();
-                let salt = hasher.generate_salt_argon2(username, password);
-                let hashed_username = hasher.hash_username(&username);
-                let hashed_password = hasher.hash_password(password, &salt);
-    
-                //push to db
-                match hashed_password{
-                    Ok(hash) => {
-
-                        loop{
-                            let user_id = Uuid::new_v4();
-
-                            //check if generated id exists in database
-                            if database_handler.id_exists(&String::from("user"), &user_id).is_ok_and(|x| x == false){
-                                let user = User::new(user_id, hashed_username, hash, 0, salt);
-                                
-                                //if not exists insert
-                                match database_handler.insert_user(user){
-                                    Ok(rows) => {
-                                        //redirect to login
-                                        println!("User {:?}", rows);
-                                        return HttpResponse::Created()
-                                        .status(StatusCode::CREATED)
-                                        .json("Status : User created.")
-                                    },
-                                    Err(error) => {
-                                        println!("Error while inserting user to database: {:?}", error);
-                                        return HttpResponse::InternalServerError()
-                                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                        .json("Status : Database error.")
-                                    },
-                                }
-                            }
-                        }
-                    },
-                    Err(error) => {
-                        println!("Error while hashing password: {:?}", error);
-                        return HttpResponse::InternalServerError()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .json("Status : Hasher error.")
-                    },
+    let email = match credentials.data.email.as_deref().and_then(normalize_email){
+        Some(email) => email,
+        None => {
+            return Err(AuthError::InvalidEmail)
+        },
+    };
+
+    if !sanitize(password, &config.password_policy){
+        return Err(AuthError::InvalidPassword)
+    }
+
+    let precheck = database.run({
+        let keys = keys.clone();
+        let username = username.clone();
+        let email = email.clone();
+
+        move |database_handler| {
+            if database_handler.email_exists(&email)?{
+                return Ok(Precheck::Refused(AuthError::EmailTaken))
+            }
+
+            if username_taken(database_handler, &keys, &username)?{
+                return Ok(Precheck::Refused(AuthError::UsernameTaken))
+            }
+
+            return Ok(Precheck::Passed(()))
+        }
+    }).await;
+
+    match precheck{
+        Ok(Precheck::Passed(())) => {},
+        Ok(Precheck::Refused(error)) => {
+            return Err(error)
+        },
+        Err(error) => {
+            println!("Error while checking username: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+
+    let hashed_username = keys.index(&username);
+    let (hash, salt) = hash_new_password(&config.argon2, &peppers, password).await?;
+
+    //push to db
+    let inserted = database.run(move |database_handler| {
+        loop{
+            let user_id = Uuid::new_v4();
+
+            //check if generated id exists in database
+            if !database_handler.id_exists("user", &user_id)?{
+                let display_username = keys.display(&user_id, &username);
+                let user = User::new(user_id, hashed_username, hash, 0, salt, Some(email.clone()), false, display_username, false);
+                
+                //if not exists insert
+                let rows = database_handler.insert_user(user)?;
+
+                if let Err(error) = issue_email_verification(database_handler, &config.email, &user_id, &email){
+                    println!("Error while issuing email verification: {:?}", error);
                 }
+
+                return Ok(rows)
+            }
+        }
+    }).await;
+
+    match inserted{
+        Ok(rows) => {
+            //redirect to login
+            println!("User {:?}", rows);
+            return Ok(HttpResponse::Created()
+            .status(StatusCode::CREATED)
+            .json("Status : User created."))
+        },
+        Err(error) => match conflict(&error){
+            Some(conflict) => return Err(conflict),
+            None => {
+                println!("Error while inserting user to database: {:?}", error);
+                return Err(AuthError::Database(error))
             },
-            Err(error) => {
-                //HTTP ERROR response
-                println!("Error while opening database: {:?}", error);
-                return HttpResponse::InternalServerError()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .json("Status : Database error. Connection dropped.")
-            },
+        },
+    }
+}
+
+
+///Handler that servers guest users.
+pub async fn guest_credentials<B: Backend>(session: Session, config: web::Data<ServerConfig>, database: web::Data<B>) -> Result<HttpResponse, AuthError> {
+    let manager = SessionManager::new(&config.session);
+    let cookie = cookie_ids(&session);
+
+    let guest = database.run(move |database_handler| {
+        //name and value valid. Not the usual case.
+        //: If matching guest ids, renew session
+        if let Some((session_id, guest_id)) = cookie{
+            let live = match database_handler.get_guest(&guest_id)?{
+                Some(guest) => guest.get_session_id().eq(&session_id) && *guest.get_expires_at() > unix_now(),
+                None => false,
+            };
+
+            if live{
+                let guest_session = manager.renew_guest_session(&guest_id);
+
+                if database_handler.renew_guest(&session_id, &guest_session)? == 1{
+                    return Ok(GuestSession::Renewed(guest_session))
+                }
+            }
+
+            //A logged in user becoming a guest, end the session the cookie held as /logout does
+            let rows = database_handler.delete_session(&session_id, &guest_id)?;
+            if rows > 0{
+                println!("Deleted sessions: {:?}", rows);
+            }
         }
+
+        //Invalid, or dont exist. Most likely scenario.
+        //Guest was cleaned up or the cookie is stale, start over with a new identity
+        return Ok(GuestSession::Created(new_guest(&manager, database_handler)?))
+    }).await;
+
+    let (guest_session, response) = match guest{
+        Ok(GuestSession::Renewed(guest_session)) => (guest_session, HttpResponse::Ok().status(StatusCode::OK).json("Status: Guest session renewed.")),
+        Ok(GuestSession::Created(guest_session)) => (guest_session, HttpResponse::Created().status(StatusCode::CREATED).json("Status: Guest user accepted.")),
+        Err(error) => {
+            println!("Error while fetching guest: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    };
+
+    let inserted = session.insert("name", guest_session.get_id().to_string())
+        .and_then(|_| session.insert("value", guest_session.get_user_id().to_string()));
+
+    if let Err(error) = inserted{
+        return Err(AuthError::Session(error.to_string()))
     }
 
-    return HttpResponse::BadRequest()
-    .status(StatusCode::BAD_REQUEST)
-    .json("Status : Invalid password.")
+    session.renew();
+    return Ok(response)
 }
 
+///Issue a fresh guest identity and store it in the database.
+fn new_guest(manager: &SessionManager, database_handler: &impl Store) -> Result<DbSession, rusqlite::Error> {
+    loop{
+        let mut taken = false;
+        let guest_session = manager.guest_session();
 
-///Handler that servers guest users.
-pub async fn guest_credentials(session: Session) -> impl Responder {
-    let manager = SessionManager::new();
-    //Get session name and value.
-    let name = session.get::<String>("name");
-    let value = session.get::<String>("value");
-
-    match DatabaseHandler::new(){
-        Ok(database_handler) => {
-            let valid_name = name.is_ok_and(|x| x.is_some());
-            let valid_value = value.is_ok_and(|x| x.is_some());
-            
-            match valid_name && valid_value{
-                //name and value valid. Not the usual case. 
-                //: If matching guest ids, renew session
-                true => todo!(),
-                //Invalid, or dont exist. Most likely scenario.
-                false => {
-                    loop{
-                        let mut valid = false;
-                        let guest_session = manager.guest_session();
-
-                        valid |= database_handler.id_exists(&"user".to_string(), guest_session.get_user_id()).is_ok_and(|x| x);
-                        valid |= database_handler.id_exists(&"session".to_string(), guest_session.get_id()).is_ok_and(|x| x);
-                        
-                        //Guest id and session id don't exist in database.
-                        if !valid{
-                            let name_op = session.insert("name", guest_session.get_id().to_string());
-                            let value_op = session.insert("value", guest_session.get_user_id().to_string());
-                            
-                            if name_op.is_ok() && value_op.is_ok(){
-                                let db_result = database_handler.insert_guest(guest_session.get_id(), guest_session.get_user_id());
-                                
-                                if db_result.is_ok(){
-                                    return HttpResponse::Accepted()
-                                        .status(StatusCode::OK)
-                                        .json("Status: Guest user accepted.")
-                                }
-                            }
-                                                       
-                        }
-                    }
-                    
-                },
+        taken |= database_handler.id_exists("user", guest_session.get_user_id())?;
+        taken |= database_handler.id_exists("guest", guest_session.get_user_id())?;
+        taken |= database_handler.id_exists("session", guest_session.get_id())?;
+        
+        //Guest id and session id don't exist in database.
+        if !taken{
+            database_handler.insert_guest(&guest_session)?;
+            return Ok(guest_session)
+        }
+    }
+}
+
+
+///Handler that registers the guest making the request.
+///The new user keeps the guest id, and the guest session becomes a regular session.
+pub async fn upgrade_guest<B: Backend>(session: Session, config: web::Data<ServerConfig>, database: web::Data<B>, keys: web::Data<UsernameKeys>, peppers: web::Data<Peppers>, credentials: CredentialsPayload) -> Result<HttpResponse, AuthError> {
+    let username = credentials.data.username.clone();
+    let password = &credentials.data.password;
+
+    let (session_id, guest_id) = match cookie_ids(&session){
+        Some(ids) => ids,
+        None => {
+            return Err(AuthError::NoGuestSession)
+        },
+    };
+
+    let email = match credentials.data.email.as_deref().and_then(normalize_email){
+        Some(email) => email,
+        None => {
+            return Err(AuthError::InvalidEmail)
+        },
+    };
+
+    if !sanitize(password, &config.password_policy){
+        return Err(AuthError::InvalidPassword)
+    }
+
+    let precheck = database.run({
+        let keys = keys.clone();
+        let username = username.clone();
+        let email = email.clone();
+
+        move |database_handler| {
+            if database_handler.id_exists("user", &guest_id)?{
+                return Ok(Precheck::Refused(AuthError::AlreadyRegistered))
+            }
+
+            if database_handler.email_exists(&email)?{
+                return Ok(Precheck::Refused(AuthError::EmailTaken))
             }
+
+            //Cookie must match a live guest row
+            let guest = match database_handler.get_guest(&guest_id)?{
+                Some(guest) if guest.get_session_id().eq(&session_id) && *guest.get_expires_at() > unix_now() => guest,
+                _ => return Ok(Precheck::Refused(AuthError::NoGuestSession)),
+            };
+
+            if username_taken(database_handler, &keys, &username)?{
+                return Ok(Precheck::Refused(AuthError::UsernameTaken))
+            }
+
+            return Ok(Precheck::Passed(guest))
+        }
+    }).await;
+
+    let guest = match precheck{
+        Ok(Precheck::Passed(guest)) => guest,
+        Ok(Precheck::Refused(error)) => {
+            return Err(error)
         },
         Err(error) => {
-            //HTTP ERROR response
-            println!("Error while opening database: {:?}", error);
-            return HttpResponse::InternalServerError()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .json("Status : Database error. Connection dropped.")
+            println!("Error while checking guest: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    };
+
+    let hashed_username = keys.index(&username);
+    let (hash, salt) = hash_new_password(&config.argon2, &peppers, password).await?;
+
+    let display_username = keys.display(&guest_id, &username);
+    let user = User::new(guest_id, hashed_username, hash, 0, salt, Some(email.clone()), false, display_username, false);
+    let user_session = SessionManager::new(&config.session).adopt_session(&session_id, &guest_id);
+
+    let upgraded = database.run({
+        let config = config.clone();
+
+        move |database_handler| {
+            let rows = database_handler.upgrade_guest(user, &user_session)?;
+
+            if let Err(error) = issue_email_verification(database_handler, &config.email, &guest_id, &email){
+                println!("Error while issuing email verification: {:?}", error);
+            }
+
+            return Ok(rows)
         }
+    }).await;
+
+    match upgraded{
+        Ok(rows) => {
+            println!("Upgraded guest {:?} created at {}: {:?}", guest.get_id(), guest.get_created_at(), rows);
+            session.renew();
+
+            return Ok(HttpResponse::Created()
+            .status(StatusCode::CREATED)
+            .json("Status : User created."))
+        },
+        Err(error) => match conflict(&error){
+            Some(conflict) => return Err(conflict),
+            None => {
+                println!("Error while upgrading guest: {:?}", error);
+                return Err(AuthError::Database(error))
+            },
+        },
     }
 }
 
 
-///Basic function to see if one of each key type is present.
+#[derive(Deserialize)]
+pub struct UsernameQuery{
+    username: String
+}
+
+#[derive(Serialize)]
+struct UsernameAvailability{
+    available: bool
+}
+
+///Handler that tells the client whether a username can still be registered.
+pub async fn username_available<B: Backend>(database: web::Data<B>, keys: web::Data<UsernameKeys>, query: web::Query<UsernameQuery>) -> Result<HttpResponse, AuthError> {
+    let username = query.into_inner().username;
+
+    if username.is_empty(){
+        return Err(AuthError::InvalidUsername)
+    }
+
+    match database.run(move |database_handler| Ok(username_taken(database_handler, &keys, &username)?)).await{
+        Ok(exists) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(UsernameAvailability { available: !exists }))
+        },
+        Err(error) => {
+            println!("Error while checking username: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
+
+
+///Whether a username is registered, under its blind index or a legacy index.
+fn username_taken(database_handler: &impl UserStore, keys: &UsernameKeys, username: &str) -> Result<bool, rusqlite::Error>{
+    return Ok(database_handler.username_exists(&keys.index(username))? || database_handler.username_exists(&legacy_index(username))?)
+}
+
+///Basic function to see if one of each key type required by the policy is present.
 ///Client side sanitization is also implemented.
-pub fn sanitize(password: &String) -> bool{
+pub fn sanitize(password: &str, policy: &PasswordPolicy) -> bool{
     let mut has_length = false;
     let mut has_digit = false;
     let mut has_letter = false;
@@ -290,9 +573,12 @@ pub fn sanitize(password: &String) -> bool{
         }
     });
 
-    if password.len() >= 8{
+    if password.chars().count() >= policy.min_length{
         has_length = true;
     }
     
-    return has_length && has_digit && has_letter && has_special_char
+    return has_length
+        && (has_digit || !policy.require_digit)
+        && (has_letter || !policy.require_letter)
+        && (has_special_char || !policy.require_special)
 }
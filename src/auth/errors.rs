@@ -0,0 +1,298 @@
+use std::fmt;
+
+use actix_web::{error::{JsonPayloadError, PayloadError, UrlencodedError}, http::{header::RETRY_AFTER, StatusCode}, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::{database::store::DatabaseError, utils::request_id::current_request_id};
+
+use super::lockout::Block;
+
+
+///Reason a request failed. Answered with the status for the reason and the error envelope.
+#[derive(Debug)]
+pub enum AuthError{
+    InvalidUsername,
+    InvalidPassword,
+    InvalidEmail,
+    InvalidToken,
+    SamePassword,
+    MissingUnlockTarget,
+    ///The body could not be read as the expected fields. Holds what was wrong, by field where known.
+    InvalidBody(Vec<FieldError>),
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    InvalidCredentials,
+    ///The old password of a password change does not match.
+    IncorrectPassword,
+    EmailNotVerified,
+    NoSession,
+    SessionExpired,
+    NoGuestSession,
+    ///The cookie belongs to another user than the one logging in.
+    SessionMismatch,
+    AdminDisabled,
+    InvalidAdminToken,
+    UsernameTaken,
+    EmailTaken,
+    AlreadyRegistered,
+    UnknownJob,
+    JobRunning,
+    ///Too many failed logins. Holds the seconds until the next attempt.
+    Throttled(i64),
+    ///The account is locked. Holds the seconds until the lock ends.
+    Locked(i64),
+    ///The route's rate limit is used up. Holds the seconds until the next token.
+    RateLimited(u64),
+    Database(DatabaseError),
+    Hasher(String),
+    Session(String),
+    Internal(&'static str),
+}
+
+///Body of every error response. `code` is stable for clients to match on, `message` is for people.
+#[derive(Serialize)]
+pub struct ErrorBody{
+    code: &'static str,
+    message: &'static str,
+    request_id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+///Problem with one field of a request body. `field` is absent when the body as a whole is malformed.
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    message: String,
+}
+
+impl FieldError{
+    ///Read the field from a deserializer message such as "missing field `username`".
+    fn from_message(message: String) -> Self{
+        let field = message.split_once(" field `")
+            .and_then(|(_, rest)| rest.split_once('`'))
+            .map(|(field, _)| field.to_string());
+
+        FieldError { field: field, message: message }
+    }
+}
+
+impl AuthError{
+    ///Machine readable code of the error.
+    pub fn code(&self) -> &'static str{
+        match self{
+            AuthError::InvalidUsername => "invalid_username",
+            AuthError::InvalidPassword => "invalid_password",
+            AuthError::InvalidEmail => "invalid_email",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::SamePassword => "same_password",
+            AuthError::MissingUnlockTarget => "missing_unlock_target",
+            AuthError::InvalidBody(_) => "invalid_body",
+            AuthError::PayloadTooLarge => "payload_too_large",
+            AuthError::UnsupportedMediaType => "unsupported_media_type",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::IncorrectPassword => "incorrect_password",
+            AuthError::EmailNotVerified => "email_not_verified",
+            AuthError::NoSession => "no_session",
+            AuthError::SessionExpired => "session_expired",
+            AuthError::NoGuestSession => "no_guest_session",
+            AuthError::SessionMismatch => "session_mismatch",
+            AuthError::AdminDisabled => "admin_disabled",
+            AuthError::InvalidAdminToken => "invalid_admin_token",
+            AuthError::UsernameTaken => "username_taken",
+            AuthError::EmailTaken => "email_taken",
+            AuthError::AlreadyRegistered => "already_registered",
+            AuthError::UnknownJob => "unknown_job",
+            AuthError::JobRunning => "job_running",
+            AuthError::Throttled(_) => "too_many_attempts",
+            AuthError::Locked(_) => "account_locked",
+            AuthError::RateLimited(_) => "rate_limited",
+            AuthError::Database(DatabaseError::Pool(_)) => "database_unavailable",
+            AuthError::Database(_) => "database_error",
+            AuthError::Hasher(_) => "hasher_error",
+            AuthError::Session(_) => "session_error",
+            AuthError::Internal(_) => "internal_error",
+        }
+    }
+
+    ///Message shown to the client. Server side causes are only logged.
+    pub fn message(&self) -> &'static str{
+        match self{
+            AuthError::InvalidUsername => "Invalid username.",
+            AuthError::InvalidPassword => "Invalid password.",
+            AuthError::InvalidEmail => "Invalid email.",
+            AuthError::InvalidToken => "Invalid or expired token.",
+            AuthError::SamePassword => "New password must differ from the current one.",
+            AuthError::MissingUnlockTarget => "Username or ip required.",
+            AuthError::InvalidBody(_) => "Invalid request body.",
+            AuthError::PayloadTooLarge => "Request body too large.",
+            AuthError::UnsupportedMediaType => "Unsupported content type.",
+            AuthError::InvalidCredentials => "Invalid credentials.",
+            AuthError::IncorrectPassword => "Incorrect password.",
+            AuthError::EmailNotVerified => "Email not verified.",
+            AuthError::NoSession => "No active session.",
+            AuthError::SessionExpired => "Session expired.",
+            AuthError::NoGuestSession => "No guest session.",
+            AuthError::SessionMismatch => "Session belongs to another user.",
+            AuthError::AdminDisabled => "Admin endpoints are disabled.",
+            AuthError::InvalidAdminToken => "Invalid admin token.",
+            AuthError::UsernameTaken => "Username taken.",
+            AuthError::EmailTaken => "Email already registered.",
+            AuthError::AlreadyRegistered => "Already registered.",
+            AuthError::UnknownJob => "No such job.",
+            AuthError::JobRunning => "Job is already running.",
+            AuthError::Throttled(_) => "Too many failed attempts. Try again later.",
+            AuthError::Locked(_) => "Account locked. Try again later.",
+            AuthError::RateLimited(_) => "Too many requests. Try again later.",
+            AuthError::Database(DatabaseError::Pool(_)) => "Database unavailable. Try again later.",
+            AuthError::Database(_) => "Database error.",
+            AuthError::Hasher(_) => "Hasher error.",
+            AuthError::Session(_) => "Error during session creation.",
+            AuthError::Internal(_) => "Internal error.",
+        }
+    }
+
+    ///Seconds the client should wait before retrying, for errors that end by themselves.
+    fn retry_after(&self) -> Option<u64>{
+        match self{
+            AuthError::Throttled(seconds) | AuthError::Locked(seconds) => Some((*seconds).max(1) as u64),
+            AuthError::RateLimited(seconds) => Some(*seconds),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            AuthError::Database(error) => write!(f, "{}: {}", self.code(), error),
+            AuthError::Hasher(error) | AuthError::Session(error) => write!(f, "{}: {}", self.code(), error),
+            AuthError::Internal(cause) => write!(f, "{}: {}", self.code(), cause),
+            _ => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+impl ResponseError for AuthError{
+    fn status_code(&self) -> StatusCode {
+        match self{
+            AuthError::InvalidUsername
+            | AuthError::InvalidPassword
+            | AuthError::InvalidEmail
+            | AuthError::InvalidToken
+            | AuthError::SamePassword
+            | AuthError::MissingUnlockTarget
+            | AuthError::InvalidBody(_) => StatusCode::BAD_REQUEST,
+            AuthError::InvalidCredentials
+            | AuthError::NoSession
+            | AuthError::SessionExpired
+            | AuthError::NoGuestSession
+            | AuthError::InvalidAdminToken => StatusCode::UNAUTHORIZED,
+            AuthError::IncorrectPassword | AuthError::EmailNotVerified => StatusCode::FORBIDDEN,
+            AuthError::AdminDisabled | AuthError::UnknownJob => StatusCode::NOT_FOUND,
+            AuthError::SessionMismatch
+            | AuthError::UsernameTaken
+            | AuthError::EmailTaken
+            | AuthError::AlreadyRegistered
+            | AuthError::JobRunning => StatusCode::CONFLICT,
+            AuthError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AuthError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AuthError::Locked(_) => StatusCode::LOCKED,
+            AuthError::Throttled(_) | AuthError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::Database(DatabaseError::Pool(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::Database(_) | AuthError::Hasher(_) | AuthError::Session(_) | AuthError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let request_id = current_request_id();
+        let status = self.status_code();
+
+        if status.is_server_error(){
+            println!("Request {} failed: {}", request_id, self);
+        }
+
+        let fields = match self{
+            AuthError::InvalidBody(fields) => fields.clone(),
+            _ => vec![],
+        };
+
+        let mut response = HttpResponse::build(status);
+
+        if let Some(seconds) = self.retry_after(){
+            response.insert_header((RETRY_AFTER, seconds.to_string()));
+        }
+
+        return response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id: request_id,
+            fields: fields
+        })
+    }
+}
+
+impl From<DatabaseError> for AuthError{
+    fn from(error: DatabaseError) -> Self {
+        AuthError::Database(error)
+    }
+}
+
+impl From<JsonPayloadError> for AuthError{
+    fn from(error: JsonPayloadError) -> Self {
+        match error{
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => AuthError::PayloadTooLarge,
+            JsonPayloadError::ContentType => AuthError::UnsupportedMediaType,
+            JsonPayloadError::Deserialize(error) => AuthError::InvalidBody(vec![FieldError::from_message(error.to_string())]),
+            JsonPayloadError::Payload(error) => AuthError::from(error),
+            _ => AuthError::Internal("json body error"),
+        }
+    }
+}
+
+impl From<UrlencodedError> for AuthError{
+    fn from(error: UrlencodedError) -> Self {
+        match error{
+            UrlencodedError::Overflow { .. } => AuthError::PayloadTooLarge,
+            UrlencodedError::ContentType => AuthError::UnsupportedMediaType,
+            UrlencodedError::Parse(error) => AuthError::InvalidBody(vec![FieldError::from_message(error.to_string())]),
+            UrlencodedError::Payload(error) => AuthError::from(error),
+            UrlencodedError::Serialize(_) => AuthError::Internal("form body error"),
+            _ => AuthError::InvalidBody(vec![]),
+        }
+    }
+}
+
+impl From<PayloadError> for AuthError{
+    fn from(error: PayloadError) -> Self {
+        match error{
+            PayloadError::Overflow => AuthError::PayloadTooLarge,
+            _ => AuthError::InvalidBody(vec![]),
+        }
+    }
+}
+
+impl From<Block> for AuthError{
+    fn from(block: Block) -> Self {
+        match block{
+            Block::Throttled(seconds) => AuthError::Throttled(seconds),
+            Block::Locked(seconds) => AuthError::Locked(seconds),
+        }
+    }
+}
+
+///Error for a unique constraint violated on insert: a taken username or a registered email.
+///None for any other error.
+pub fn conflict(error: &DatabaseError) -> Option<AuthError>{
+    match error{
+        DatabaseError::Query(rusqlite::Error::SqliteFailure(failure, message)) if failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE => {
+            if message.as_deref().is_some_and(|message| message.contains("user.username")){
+                return Some(AuthError::UsernameTaken)
+            }
+
+            return Some(AuthError::EmailTaken)
+        },
+        _ => return None,
+    }
+}
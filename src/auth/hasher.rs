@@ -1,52 +1,183 @@
-use argon2::{password_hash::{Error, SaltString}, Argon2, PasswordHasher};
-use rand::Rng;
-use sha2::{Digest, Sha256};
-
-const ARRAY_SIZE: usize = 16;
-const HALF_SIZE: usize = 8;
-///Object that implements the hashing functions. Sha256 and argon2 are generally used.
-pub struct Hasher{}
-impl Hasher{
+use actix_web::web;
+use argon2::{password_hash::{errors::InvalidValue, Error, Salt, SaltString}, Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use rand::rngs::OsRng;
+
+use crate::config::server_config::Argon2Settings;
+
+use super::{errors::AuthError, pepper::Peppers};
+
+///Run `task` with a hasher on the blocking thread pool.
+///Argon2 is slow on purpose, every hash and verification goes through here to keep it off the request threads.
+pub async fn run_hasher<T, F>(settings: &Argon2Settings, peppers: &web::Data<Peppers>, task: F) -> Result<T, AuthError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Hasher) -> T + Send + 'static,
+{
+    let settings = settings.clone();
+    let peppers = peppers.clone();
+
+    match web::block(move || task(&Hasher::new(&settings, &peppers))).await{
+        Ok(result) => return Ok(result),
+        Err(error) => {
+            println!("Error while hashing password: {:?}", error);
+            return Err(AuthError::Hasher(error.to_string()))
+        },
+    }
+}
+
+///Hash a new password with a fresh salt, off the request threads.
+pub async fn hash_new_password(settings: &Argon2Settings, peppers: &web::Data<Peppers>, password: &str) -> Result<(String, SaltString), AuthError>{
+    let password = password.to_string();
+
+    let hashed = run_hasher(settings, peppers, move |hasher| {
+        let salt = hasher.random_salt();
+        return hasher.hash_password(&password, &salt).map(|hash| (hash, salt))
+    }).await?;
+
+    match hashed{
+        Ok(hashed) => return Ok(hashed),
+        Err(error) => {
+            println!("Error while hashing password: {:?}", error);
+            return Err(AuthError::Hasher(error.to_string()))
+        },
+    }
+}
+
+///Object that implements the hashing functions. Argon2 is used for passwords, peppered with the current pepper.
+pub struct Hasher<'a>{
+    argon: Argon2<'a>,
+    algorithm: Algorithm,
+    peppers: &'a Peppers
+}
+impl<'a> Hasher<'a>{
     
-    ///New hasher instance.
-    pub fn new() -> Hasher{
-        Hasher {}
-    }
-
-    ///Salt generation from argon2.
-    pub fn generate_salt_argon2(&mut self, username: &String, password: &String) -> SaltString{
-        let random_bytes: [u8;ARRAY_SIZE] = rand::thread_rng().r#gen();
-        let credentials = format!("{}{}{}", username, password, hex::encode(random_bytes));
-        match SaltString::encode_b64(credentials.as_bytes()){
-            Ok(result) => {
-                return result
+    ///New hasher instance using the configured argon2 variant and costs.
+    ///Settings are validated at startup, invalid ones fall back to the argon2 defaults.
+    pub fn new(settings: &Argon2Settings, peppers: &'a Peppers) -> Hasher<'a>{
+        let params = settings.params().unwrap_or_default();
+        let algorithm = settings.variant.into();
+
+        //The pepper id is stored in the hash as keyid, so verification knows which pepper to use
+        let peppered = peppers.current().map(|(id, pepper)| {
+            with_keyid(&params, id).and_then(|params| Argon2::new_with_secret(pepper, algorithm, Version::V0x13, params))
+        });
+
+        let argon = match peppered{
+            Some(Ok(argon)) => argon,
+            Some(Err(error)) => {
+                println!("Error while applying pepper, hashing without: {:?}", error);
+                Argon2::new(algorithm, Version::V0x13, params)
+            },
+            None => Argon2::new(algorithm, Version::V0x13, params),
+        };
+
+        Hasher {
+            argon: argon,
+            algorithm: algorithm,
+            peppers: peppers
+        }
+    }
+
+    ///Random salt from the operating system, independent of the credentials.
+    ///Salts used to be derived from the username and password, see `is_legacy_salt`.
+    pub fn random_salt(&self) -> SaltString{
+        return SaltString::generate(&mut OsRng)
+    }
+
+    ///Check a password against a stored PHC string in constant time.
+    ///Variant, costs, salt and pepper id are read from the string, so hashes made with older settings still verify.
+    pub fn verify_password(&self, password: &str, stored: &str) -> bool{
+        let hash = match PasswordHash::new(stored){
+            Ok(hash) => hash,
+            Err(error) => {
+                println!("Malformed password hash: {:?}", error);
+                return false
             },
-            //If encoding fails, retry with half the size
+        };
+
+        let verifier = match pepper_id(&hash){
+            Ok(Some(id)) => match self.peppers.get(id){
+                Some(pepper) => Argon2::new_with_secret(pepper, Algorithm::default(), Version::default(), Params::default()),
+                None => {
+                    println!("Password hash uses unknown pepper {}.", id);
+                    return false
+                },
+            },
+            Ok(None) => Ok(Argon2::default()),
             Err(error) => {
-                println!("Encoding failed: {:?}\nAttempting with half size...", error);
-                let halved_random_bytes: [u8;HALF_SIZE] = rand::thread_rng().r#gen();
-                let new_credentials = format!("{}{}{}", username, password, hex::encode(halved_random_bytes));
-                return SaltString::encode_b64(new_credentials.as_bytes()).unwrap()
+                println!("Malformed pepper id in password hash: {:?}", error);
+                return false
+            },
+        };
+
+        match verifier{
+            Ok(verifier) => return verifier.verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(error) => {
+                println!("Error while verifying password hash: {:?}", error);
+                return false
             },
         }
     }
 
-    ///Function that hashed a password.
-    pub fn hash_username(&self, username: &String) -> String{
-        let mut hasher = Sha256::new();
-        hasher.update(username.as_bytes());
-        let hash: String = format!("{:X}", hasher.finalize());
-        
-        return hash
+    ///Whether a stored hash was made with another variant, version, costs or pepper than the configured ones.
+    pub fn needs_rehash(&self, stored: &str) -> bool{
+        let hash = match PasswordHash::new(stored){
+            Ok(hash) => hash,
+            Err(_) => return true,
+        };
+
+        let params = match Params::try_from(&hash){
+            Ok(params) => params,
+            Err(_) => return true,
+        };
+
+        let current = self.argon.params();
+
+        return hash.algorithm != self.algorithm.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+            || params.keyid() != current.keyid()
+            || hash.hash.is_none_or(|output| output.len() != current.output_len().unwrap_or(Params::DEFAULT_OUTPUT_LEN))
     }
 
     ///Function that hashed a password based on a salt.
-    pub fn hash_password(&self, password: &String, salt: &SaltString) -> Result<String, Error>{
-        let argon = Argon2::default();
-        
-        match argon.hash_password(password.as_bytes(), salt){
+    pub fn hash_password(&self, password: &str, salt: &SaltString) -> Result<String, Error>{
+        match self.argon.hash_password(password.as_bytes(), salt){
             Ok(hash) => return Ok(hash.to_string()),
             Err(error) => return Err(error),
         }
     }
-}
\ No newline at end of file
+}
+
+
+///Parameters tagged with a pepper id.
+fn with_keyid(params: &Params, id: u32) -> Result<Params, argon2::Error>{
+    return ParamsBuilder::new()
+        .m_cost(params.m_cost())
+        .t_cost(params.t_cost())
+        .p_cost(params.p_cost())
+        .keyid(KeyId::new(&id.to_be_bytes())?)
+        .build()
+}
+
+///Pepper id of a stored hash. None for hashes made without a pepper.
+fn pepper_id(hash: &PasswordHash) -> Result<Option<u32>, Error>{
+    let params = Params::try_from(hash)?;
+
+    if params.keyid().is_empty(){
+        return Ok(None)
+    }
+
+    match <[u8; 4]>::try_from(params.keyid()){
+        Ok(bytes) => return Ok(Some(u32::from_be_bytes(bytes))),
+        Err(_) => return Err(Error::ParamValueInvalid(InvalidValue::Malformed)),
+    }
+}
+
+///Whether a stored salt was built from the credentials by older versions, which base64 encoded
+///the username, password and random hex. Random salts hold exactly the recommended 16 bytes.
+pub fn is_legacy_salt(salt: &str) -> bool{
+    return salt.len() * 3 / 4 != Salt::RECOMMENDED_LENGTH
+}
@@ -0,0 +1,254 @@
+use std::{fs, io::{self, Write}, path::Path};
+
+use actix_web::{body::MessageBody, cookie::{Cookie, CookieJar, Key}, dev::{ServiceRequest, ServiceResponse}, http::header::{HeaderValue, COOKIE, SET_COOKIE}, middleware::Next, web, Error};
+
+use crate::{config::server_config::{CookieSettings, ServerConfig}, utils::time::{unix_now, SECONDS_PER_DAY}};
+
+///Size of a cookie master key in bytes.
+const KEY_SIZE: usize = 64;
+
+
+///Cookie key with the time it was generated.
+struct RingKey{
+    created_at: i64,
+    key: Key
+}
+
+///Cookie master keys, newest first.
+///The newest key seals every cookie, older keys only open cookies until their grace period ends.
+pub struct KeyRing{
+    keys: Vec<RingKey>,
+    grace_seconds: i64
+}
+
+impl KeyRing{
+    ///Load the key ring described by the cookie settings.
+    ///A key given in the configuration is used alone. Otherwise keys are read from the key file,
+    ///generating, rotating and pruning them as needed, and the file is rewritten on change.
+    pub fn load(settings: &CookieSettings) -> io::Result<KeyRing>{
+        let grace_seconds = settings.key_grace_days as i64 * SECONDS_PER_DAY;
+
+        if let Some(encoded) = &settings.key{
+            let key = decode_key(encoded)?;
+
+            return Ok(KeyRing {
+                keys: vec![RingKey { created_at: unix_now(), key: key }],
+                grace_seconds: grace_seconds
+            })
+        }
+
+        let path = Path::new(&settings.key_file);
+        let mut ring = KeyRing {
+            keys: read_key_file(path)?,
+            grace_seconds: grace_seconds
+        };
+        let now = unix_now();
+        let mut changed = false;
+
+        let rotation_seconds = settings.key_rotation_days as i64 * SECONDS_PER_DAY;
+        let rotation_due = ring.keys.first()
+            .is_some_and(|newest| rotation_seconds > 0 && now - newest.created_at >= rotation_seconds);
+
+        if ring.keys.is_empty() || rotation_due{
+            println!("Generating new session cookie key...");
+            ring.keys.insert(0, RingKey { created_at: now, key: Key::generate() });
+            changed = true;
+        }
+
+        let total = ring.keys.len();
+        ring.prune(now);
+        changed |= total != ring.keys.len();
+
+        if changed{
+            write_key_file(path, &ring.keys)?;
+        }
+        restrict_permissions(path)?;
+
+        return Ok(ring)
+    }
+
+    ///Key that seals new cookies.
+    pub fn current(&self) -> &Key{
+        return &self.keys[0].key
+    }
+
+    ///Drop retired keys whose grace period has ended. A key retires when its successor is created.
+    fn prune(&mut self, now: i64){
+        let mut kept: Vec<RingKey> = vec![];
+        let mut retired_at: Option<i64> = None;
+
+        for ring_key in self.keys.drain(..){
+            let expired = retired_at.is_some_and(|retired| now - retired >= self.grace_seconds);
+            retired_at = Some(ring_key.created_at);
+
+            if !expired{
+                kept.push(ring_key);
+            }
+            else{
+                break;
+            }
+        }
+
+        self.keys = kept;
+    }
+
+    ///Decrypt a private cookie value sealed with a retired key and seal it again with the current key.
+    fn reseal(&self, name: &str, value: &str) -> Option<String>{
+        let cookie = Cookie::new(name.to_string(), value.to_string());
+
+        if CookieJar::new().private(self.current()).decrypt(cookie.clone()).is_some(){
+            return None
+        }
+
+        for ring_key in self.keys.iter().skip(1){
+            if let Some(opened) = CookieJar::new().private(&ring_key.key).decrypt(cookie.clone()){
+                let mut jar = CookieJar::new();
+                jar.private_mut(self.current()).add(opened);
+
+                return jar.get(name).map(|sealed| sealed.value().to_string())
+            }
+        }
+
+        return None
+    }
+}
+
+
+///Middleware that keeps cookies sealed with a retired key readable.
+///The request cookie is resealed with the current key before the session middleware reads it,
+///and sent back to the client unless the response already sets the session cookie.
+pub async fn reseal_session_cookie(
+    ring: web::Data<KeyRing>,
+    config: web::Data<ServerConfig>,
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let name = &config.cookie.name;
+    let mut resealed: Option<String> = None;
+    let mut pieces: Vec<String> = vec![];
+
+    for header in req.headers().get_all(COOKIE){
+        for piece in header.to_str().unwrap_or_default().split(';').map(|piece| piece.trim()){
+            if piece.is_empty(){
+                continue;
+            }
+
+            let sealed = Cookie::parse_encoded(piece).ok()
+                .filter(|cookie| cookie.name() == name)
+                .and_then(|cookie| ring.reseal(name, cookie.value()));
+
+            match sealed{
+                Some(value) => {
+                    pieces.push(Cookie::new(name.clone(), value.clone()).encoded().to_string());
+                    resealed = Some(value);
+                },
+                None => pieces.push(piece.to_string()),
+            }
+        }
+    }
+
+    if resealed.is_some(){
+        if let Ok(header) = HeaderValue::from_str(&pieces.join("; ")){
+            req.headers_mut().remove(COOKIE);
+            req.headers_mut().insert(COOKIE, header);
+        }
+    }
+
+    let mut res = next.call(req).await?;
+
+    if let Some(value) = resealed{
+        let prefix = format!("{}=", name);
+        let already_set = res.headers().get_all(SET_COOKIE)
+            .any(|header| header.to_str().is_ok_and(|header| header.starts_with(&prefix)));
+
+        if !already_set{
+            let cookie = Cookie::build(name.clone(), value)
+                .path("/")
+                .secure(config.cookie.secure)
+                .http_only(config.cookie.http_only)
+                .same_site(config.cookie.same_site.into())
+                .finish();
+
+            if let Ok(header) = HeaderValue::from_str(&cookie.encoded().to_string()){
+                res.headers_mut().append(SET_COOKIE, header);
+            }
+        }
+    }
+
+    return Ok(res)
+}
+
+
+///Decode a hex encoded master key.
+fn decode_key(encoded: &str) -> io::Result<Key>{
+    let bytes = hex::decode(encoded.trim())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("cookie key is not valid hex: {}", error)))?;
+
+    return Key::try_from(bytes.as_slice())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("cookie key must be at least {} bytes", KEY_SIZE)))
+}
+
+///Read keys from the key file, one `<created_at> <hex key>` pair per line, newest first.
+fn read_key_file(path: &Path) -> io::Result<Vec<RingKey>>{
+    if !path.exists(){
+        return Ok(vec![])
+    }
+
+    let mut keys: Vec<RingKey> = vec![];
+
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate(){
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: malformed key entry", path.display(), number + 1));
+        let (created_at, encoded) = line.split_once(' ').ok_or_else(invalid)?;
+
+        keys.push(RingKey {
+            created_at: created_at.parse().map_err(|_| invalid())?,
+            key: decode_key(encoded)?
+        });
+    }
+
+    keys.sort_by_key(|ring_key| std::cmp::Reverse(ring_key.created_at));
+    return Ok(keys)
+}
+
+///Write keys to a temporary owner-only file and move it over the key file.
+fn write_key_file(path: &Path, keys: &[RingKey]) -> io::Result<()>{
+    let temporary = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&temporary)?;
+    writeln!(file, "# Session cookie keys, newest first. Keep this file secret.")?;
+
+    for ring_key in keys{
+        writeln!(file, "{} {}", ring_key.created_at, hex::encode(ring_key.key.master()))?;
+    }
+
+    file.sync_all()?;
+    return fs::rename(&temporary, path)
+}
+
+///Make sure the key file is readable by its owner only.
+fn restrict_permissions(path: &Path) -> io::Result<()>{
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    return Ok(())
+}
@@ -0,0 +1,232 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use rusqlite::Error;
+use serde::Deserialize;
+
+use crate::{config::server_config::{LockoutSettings, ServerConfig}, database::store::{Backend, LoginAttemptStore, Store, TokenStore, UserStore}, mail::outbox::enqueue, models::database_models::User, utils::time::unix_now};
+
+use super::{errors::AuthError, verification::{hash_token, new_token}};
+
+///Counter scope of an account. The key is the stored username index, or the blind index of unknown usernames so they are counted as well.
+pub const ACCOUNT: &str = "account";
+///Counter scope of a client address.
+pub const IP: &str = "ip";
+///Purpose stored with account unlock tokens.
+pub const ACCOUNT_UNLOCK: &str = "unlock";
+
+
+///Reason a login attempt is refused before the password is checked. Holds the seconds until it may be retried.
+///Answered with 423 for a locked account, 429 otherwise, with Retry-After.
+#[derive(Debug, Clone, Copy)]
+pub enum Block{
+    ///Too many recent failures, the client has to wait.
+    Throttled(i64),
+    ///The account is locked.
+    Locked(i64),
+}
+
+///Failed login bookkeeping for one attempt, identified by the username hash and client address.
+pub struct Lockout<'a>{
+    settings: &'a LockoutSettings,
+    account: &'a str,
+    ip: Option<&'a str>,
+}
+
+impl<'a> Lockout<'a>{
+    pub fn new(settings: &'a LockoutSettings, account: &'a str, ip: Option<&'a str>) -> Self{
+        Lockout {
+            settings: settings,
+            account: account,
+            ip: ip
+        }
+    }
+
+    ///Whether the attempt has to be refused. Checked before any password is hashed.
+    pub fn check(&self, database_handler: &impl LoginAttemptStore) -> Result<Option<Block>, Error>{
+        if !self.settings.enabled{
+            return Ok(None)
+        }
+
+        let now = unix_now();
+
+        //Addresses back off after more free attempts than accounts, many users may share one
+        if let Some(ip) = self.ip{
+            if let Some(attempt) = database_handler.get_login_attempt(IP, ip)?{
+                if *attempt.get_locked_until() > now{
+                    return Ok(Some(Block::Throttled(attempt.get_locked_until() - now)))
+                }
+
+                let retry_at = attempt.get_last_failure_at() + self.delay(*attempt.get_failures(), self.settings.ip_free_attempts);
+
+                if retry_at > now{
+                    return Ok(Some(Block::Throttled(retry_at - now)))
+                }
+            }
+        }
+
+        if let Some(attempt) = database_handler.get_login_attempt(ACCOUNT, self.account)?{
+            if *attempt.get_locked_until() > now{
+                return Ok(Some(Block::Locked(attempt.get_locked_until() - now)))
+            }
+
+            let retry_at = attempt.get_last_failure_at() + self.delay(*attempt.get_failures(), self.settings.free_attempts);
+
+            if retry_at > now{
+                return Ok(Some(Block::Throttled(retry_at - now)))
+            }
+        }
+
+        return Ok(None)
+    }
+
+    ///Count a failed attempt, locking the account or address once its threshold is reached.
+    ///Returns true when this failure locked the account.
+    pub fn record_failure(&self, database_handler: &impl LoginAttemptStore) -> Result<bool, Error>{
+        if !self.settings.enabled{
+            return Ok(false)
+        }
+
+        let now = unix_now();
+        let locked_until = now + self.settings.lockout_seconds();
+
+        if let Some(ip) = self.ip{
+            let attempt = database_handler.record_login_failure(IP, ip, now, self.settings.window_seconds())?;
+
+            if *attempt.get_failures() >= self.settings.ip_threshold as i64{
+                println!("Blocking address {} after {} failed logins.", ip, attempt.get_failures());
+                database_handler.lock_login(IP, ip, locked_until)?;
+            }
+        }
+
+        let attempt = database_handler.record_login_failure(ACCOUNT, self.account, now, self.settings.window_seconds())?;
+
+        if *attempt.get_failures() >= self.settings.account_threshold as i64{
+            println!("Locking account {} after {} failed logins.", self.account, attempt.get_failures());
+            database_handler.lock_login(ACCOUNT, self.account, locked_until)?;
+            return Ok(true)
+        }
+
+        return Ok(false)
+    }
+
+    ///Forget the failures of the account after a successful login. Address counters are kept.
+    pub fn record_success(&self, database_handler: &impl LoginAttemptStore) -> Result<usize, Error>{
+        return database_handler.clear_login_attempts(ACCOUNT, self.account)
+    }
+
+    ///Seconds to wait after `failures` failed attempts, the first `free_attempts` of them without delay.
+    fn delay(&self, failures: i64, free_attempts: u32) -> i64{
+        let free = free_attempts as i64;
+
+        if failures <= free{
+            return 0
+        }
+
+        let exponent = (failures - free - 1).min(32) as u32;
+        let delay = self.settings.base_delay_seconds.saturating_mul(1u64 << exponent);
+
+        return delay.min(self.settings.max_delay_seconds) as i64
+    }
+}
+
+
+///Users registered under a username and the key their failed logins are counted under.
+///Accounts created before the blind index are still stored under the plain SHA-256, and are counted under it
+///so the unlock link, which only knows the stored username, clears the right counter.
+pub fn find_account(database_handler: &impl UserStore, hashed_username: String, legacy_username: String) -> Result<(String, Vec<User>), Error>{
+    let users = database_handler.get_users(&hashed_username)?;
+
+    if !users.is_empty(){
+        return Ok((hashed_username, users))
+    }
+
+    let legacy_users = database_handler.get_users(&legacy_username)?;
+
+    match legacy_users.is_empty(){
+        true => return Ok((hashed_username, legacy_users)),
+        false => return Ok((legacy_username, legacy_users)),
+    }
+}
+
+///Count a failed password check and mail the unlock link when it locks the account.
+///Returns true when this failure locked the account.
+pub fn record_failed_login(database_handler: &impl Store, config: &ServerConfig, account: &str, ip: Option<&str>) -> Result<bool, Error>{
+    let lockout = Lockout::new(&config.lockout, account, ip);
+
+    if !lockout.record_failure(database_handler)?{
+        return Ok(false)
+    }
+
+    if let Err(error) = issue_unlock(database_handler, config, account){
+        println!("Error while issuing account unlock: {:?}", error);
+    }
+
+    return Ok(true)
+}
+
+///Mail the owners of a locked account a link that lifts the lock early.
+///Accounts without an email address wait out the lock or are unlocked by an administrator.
+pub fn issue_unlock(database_handler: &impl Store, config: &ServerConfig, account: &str) -> Result<(), Error>{
+    let users = database_handler.get_users(account)?;
+
+    for user in users{
+        let email = match user.get_email(){
+            Some(email) => email,
+            None => continue,
+        };
+
+        let (token, token_hash) = new_token();
+        let now = unix_now();
+
+        database_handler.delete_verification_tokens(user.get_id(), ACCOUNT_UNLOCK)?;
+        database_handler.insert_verification_token(&token_hash, user.get_id(), ACCOUNT_UNLOCK, now, now + config.lockout.lockout_seconds())?;
+
+        let link = format!("{}/unlock-account?token={}", config.email.public_url.trim_end_matches('/'), token);
+        let body = format!(
+            "Your account was locked after repeated failed logins. If this was you, open the link below to unlock it.\n\n{}\n\nOtherwise consider changing your password. The lock ends by itself in {} minute(s).",
+            link, config.lockout.lockout_minutes
+        );
+
+        enqueue(database_handler, email, "Your account was locked", &body)?;
+    }
+
+    return Ok(())
+}
+
+
+#[derive(Deserialize)]
+pub struct UnlockQuery{
+    token: String
+}
+
+///Handler that lifts an account lock with the token from the unlock link.
+pub async fn unlock_account<B: Backend>(database: web::Data<B>, query: web::Query<UnlockQuery>) -> Result<HttpResponse, AuthError> {
+    let token_hash = hash_token(query.token.trim());
+
+    let unlocked = database.run(move |database_handler| {
+        let user_id = match database_handler.consume_verification_token(&token_hash, ACCOUNT_UNLOCK, unix_now())?{
+            Some(user_id) => user_id,
+            None => return Ok(false),
+        };
+
+        if let Some(user) = database_handler.get_user_from_id(&user_id)?{
+            database_handler.clear_login_attempts(ACCOUNT, user.get_username())?;
+        }
+
+        return Ok(true)
+    }).await;
+
+    match unlocked{
+        Ok(true) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json("Status : Account unlocked."))
+        },
+        Ok(false) => {
+            return Err(AuthError::InvalidToken)
+        },
+        Err(error) => {
+            println!("Error while unlocking account: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
@@ -0,0 +1,55 @@
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, HttpResponse};
+
+use crate::database::store::{Backend, SessionStore};
+
+use super::{errors::AuthError, sessions::{cookie_ids, AuthenticatedSession}};
+
+
+///Handler that ends the current session.
+///Deletes the matching session row and purges the cookie.
+pub async fn logout<B: Backend>(session: Session, database: web::Data<B>) -> Result<HttpResponse, AuthError> {
+    let (session_id, user_id) = match cookie_ids(&session){
+        Some(ids) => ids,
+        None => {
+            session.purge();
+            return Err(AuthError::NoSession)
+        },
+    };
+
+    match database.run(move |database_handler| Ok(database_handler.delete_session(&session_id, &user_id)?)).await{
+        Ok(rows) => {
+            println!("Deleted sessions: {:?}", rows);
+            session.purge();
+
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json("Status : Logged out."))
+        },
+        Err(error) => {
+            println!("Error while deleting session: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
+
+///Handler that revokes every session of the current user, including this one.
+///The request must carry a live session.
+pub async fn logout_all<B: Backend>(auth: AuthenticatedSession<B>, database: web::Data<B>) -> Result<HttpResponse, AuthError> {
+    let user_id = *auth.session.get_user_id();
+
+    match database.run(move |database_handler| Ok(database_handler.delete_user_sessions(&user_id)?)).await{
+        Ok(rows) => {
+            println!("Revoked sessions: {:?}", rows);
+            auth.cookie.purge();
+
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(format!("Status : Logged out of {} session(s).", rows)))
+        },
+        Err(error) => {
+            println!("Error while revoking sessions: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
\ No newline at end of file
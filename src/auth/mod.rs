@@ -1,3 +1,14 @@
 pub mod credentials;
+pub mod errors;
 pub mod hasher;
-pub mod sessions;
\ No newline at end of file
+pub mod key_ring;
+pub mod lockout;
+pub mod logout;
+pub mod password;
+pub mod pepper;
+pub mod profile;
+pub mod sessions;
+pub mod usernames;
+pub mod verification;
+#[cfg(test)]
+mod tests;
\ No newline at end of file
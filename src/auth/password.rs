@@ -0,0 +1,226 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+
+use crate::{config::server_config::ServerConfig, database::store::{Backend, Store, TokenStore, UserStore}, mail::outbox::enqueue, models::server_models::{ChangePasswordBody, ForgotPasswordBody, ResetPasswordBody}, utils::{client_ip::TrustedProxies, time::unix_now}};
+
+use super::{credentials::sanitize, errors::AuthError, hasher::{hash_new_password, run_hasher}, lockout::{record_failed_login, Lockout}, pepper::Peppers, sessions::AuthenticatedSession, verification::{hash_token, new_token, normalize_email}};
+
+///Purpose stored with password reset tokens.
+pub const PASSWORD_RESET: &str = "password_reset";
+
+
+///Handler that mails a password reset link.
+///Answers the same whether or not the address is registered, so it cannot be used to probe for accounts.
+pub async fn forgot_password<B: Backend>(config: web::Data<ServerConfig>, database: web::Data<B>, body: web::Json<ForgotPasswordBody>) -> Result<HttpResponse, AuthError> {
+    if let Some(email) = normalize_email(&body.data.email){
+        let issued = database.run(move |database_handler| Ok(issue_password_reset(database_handler, &config, &email)?)).await;
+
+        if let Err(error) = issued{
+            println!("Error while issuing password reset: {:?}", error);
+        }
+    }
+
+    return Ok(HttpResponse::Accepted()
+    .status(StatusCode::ACCEPTED)
+    .json("Status : If the address is registered, a reset link has been sent."))
+}
+
+///Store a reset token for the user registered with `email`, if any, and queue the link.
+///Links sent earlier stop working.
+fn issue_password_reset(database_handler: &impl Store, config: &ServerConfig, email: &str) -> Result<(), rusqlite::Error>{
+    let user = match database_handler.get_user_from_email(email)?{
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let (token, token_hash) = new_token();
+    let now = unix_now();
+
+    database_handler.delete_verification_tokens(user.get_id(), PASSWORD_RESET)?;
+    database_handler.insert_verification_token(&token_hash, user.get_id(), PASSWORD_RESET, now, now + config.email.reset_ttl_seconds())?;
+
+    let link = format!("{}/reset-password?token={}", config.email.public_url.trim_end_matches('/'), token);
+    let body = format!(
+        "A password reset was requested for your account. Open the link below to choose a new password.\n\n{}\n\nThe link expires in {} minute(s). If you did not ask for this, ignore this message.",
+        link, config.email.reset_ttl_minutes
+    );
+
+    enqueue(database_handler, email, "Reset your password", &body)?;
+    return Ok(())
+}
+
+
+///Handler that sets a new password with the token from a reset link.
+///Every session of the user is revoked.
+pub async fn reset_password<B: Backend>(config: web::Data<ServerConfig>, database: web::Data<B>, peppers: web::Data<Peppers>, body: web::Json<ResetPasswordBody>) -> Result<HttpResponse, AuthError> {
+    let password = &body.data.password;
+    let token_hash = hash_token(body.data.token.trim());
+
+    if !sanitize(password, &config.password_policy){
+        return Err(AuthError::InvalidPassword)
+    }
+
+    let user_id = match database.run({
+        let token_hash = token_hash.clone();
+        move |database_handler| Ok(database_handler.find_verification_token(&token_hash, PASSWORD_RESET, unix_now())?)
+    }).await{
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return Err(AuthError::InvalidToken)
+        },
+        Err(error) => {
+            println!("Error while fetching reset token: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    };
+
+    let (hash, salt) = hash_new_password(&config.argon2, &peppers, password).await?;
+
+    //The token is only used up here, together with the password change
+    match database.run(move |database_handler| Ok(database_handler.reset_password(&token_hash, PASSWORD_RESET, unix_now(), &user_id, &hash, &salt)?)).await{
+        Ok(Some(sessions)) => {
+            println!("Password reset for user {:?}, revoked {} session(s).", user_id, sessions);
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json("Status : Password changed."))
+        },
+        Ok(None) => {
+            return Err(AuthError::InvalidToken)
+        },
+        Err(error) => {
+            println!("Error while resetting password: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
+
+
+///Handler that changes the password of the logged in user.
+///Requires the current password, checked under the same throttling and lockout as logins.
+///Other sessions of the user are revoked on request.
+#[allow(clippy::too_many_arguments)]
+pub async fn change_password<B: Backend>(req: HttpRequest, auth: AuthenticatedSession<B>, config: web::Data<ServerConfig>, database: web::Data<B>, peppers: web::Data<Peppers>, proxies: web::Data<TrustedProxies>, body: web::Json<ChangePasswordBody>) -> Result<HttpResponse, AuthError> {
+    let old_password = body.data.old_password.clone();
+    let new_password = body.data.new_password.clone();
+    let user_id = *auth.session.get_user_id();
+    let client_ip = proxies.client_ip(req.peer_addr(), req.headers()).map(|address| address.to_string());
+
+    if new_password.eq(&old_password){
+        return Err(AuthError::SamePassword)
+    }
+
+    if !sanitize(&new_password, &config.password_policy){
+        return Err(AuthError::InvalidPassword)
+    }
+
+    //Refuse throttled or locked attempts before spending a hash on them
+    let lookup = database.run({
+        let config = config.clone();
+        let client_ip = client_ip.clone();
+
+        move |database_handler| {
+            let user = match database_handler.get_user_from_id(&user_id)?{
+                Some(user) => user,
+                None => return Ok(Err(AuthError::NoSession)),
+            };
+
+            let lockout = Lockout::new(&config.lockout, user.get_username(), client_ip.as_deref());
+
+            if let Some(block) = lockout.check(database_handler)?{
+                return Ok(Err(AuthError::from(block)))
+            }
+
+            return Ok(Ok(user))
+        }
+    }).await;
+
+    let user = match lookup{
+        Ok(Ok(user)) => user,
+        Ok(Err(AuthError::NoSession)) => {
+            auth.cookie.purge();
+            return Err(AuthError::NoSession)
+        },
+        Ok(Err(error)) => {
+            return Err(error)
+        },
+        Err(error) => {
+            println!("Error while fetching user: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    };
+
+    let hashed = run_hasher(&config.argon2, &peppers, {
+        let stored = user.get_password().clone();
+
+        move |hasher| {
+            //Same check as on login
+            if !hasher.verify_password(&old_password, &stored){
+                return None
+            }
+
+            let salt = hasher.random_salt();
+            return Some(hasher.hash_password(&new_password, &salt).map(|hash| (hash, salt)))
+        }
+    }).await;
+
+    let (hash, salt) = match hashed{
+        Ok(Some(Ok(hashed))) => hashed,
+        Ok(Some(Err(error))) => {
+            println!("Error while hashing password: {:?}", error);
+            return Err(AuthError::Hasher(error.to_string()))
+        },
+        Ok(None) => {
+            let locked = database.run({
+                let config = config.clone();
+                let account = user.get_username().clone();
+
+                move |database_handler| Ok(record_failed_login(database_handler, &config, &account, client_ip.as_deref())?)
+            }).await;
+
+            match locked{
+                Ok(true) => return Err(AuthError::Locked(config.lockout.lockout_seconds())),
+                Ok(false) => {},
+                Err(error) => println!("Error while recording failed password change: {:?}", error),
+            }
+
+            return Err(AuthError::IncorrectPassword)
+        },
+        Err(error) => {
+            return Err(error)
+        },
+    };
+
+    let keep_session = match body.data.revoke_other_sessions{
+        true => Some(*auth.session.get_id()),
+        false => None,
+    };
+
+    let changed = database.run({
+        let config = config.clone();
+        let account = user.get_username().clone();
+
+        move |database_handler| {
+            let lockout = Lockout::new(&config.lockout, &account, None);
+
+            if let Err(error) = lockout.record_success(database_handler){
+                println!("Error while clearing failed logins: {:?}", error);
+            }
+
+            return Ok(database_handler.change_password(&user_id, &hash, &salt, keep_session.as_ref())?)
+        }
+    }).await;
+
+    match changed{
+        Ok(sessions) => {
+            println!("Password changed for user {:?}, revoked {} session(s).", user_id, sessions);
+            auth.cookie.renew();
+
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json("Status : Password changed."))
+        },
+        Err(error) => {
+            println!("Error while changing password: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
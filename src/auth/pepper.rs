@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, fs, io::{self, Write}, path::Path};
+
+use rand::{rngs::OsRng, RngCore};
+
+use crate::config::server_config::PepperSettings;
+
+///Size of a generated pepper in bytes.
+const PEPPER_SIZE: usize = 32;
+
+
+///Numbered password peppers. The id of the pepper is stored in each hash as the argon2 `keyid`.
+pub struct Peppers{
+    keys: BTreeMap<u32, Vec<u8>>,
+    enabled: bool,
+}
+
+impl Peppers{
+    ///Load the peppers described by the settings.
+    ///With peppering disabled the key file is not generated, but existing peppers still verify old hashes.
+    pub fn load(settings: &PepperSettings) -> io::Result<Peppers>{
+        let mut keys: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+        for (id, encoded) in &settings.keys{
+            keys.insert(parse_id(id)?, decode_pepper(encoded)?);
+        }
+
+        if keys.is_empty(){
+            let path = Path::new(&settings.key_file);
+
+            if !path.exists() && settings.enabled{
+                println!("Generating new password pepper...");
+                let mut pepper = vec![0u8; PEPPER_SIZE];
+                OsRng.fill_bytes(&mut pepper);
+                write_pepper_file(path, &pepper)?;
+            }
+
+            if path.exists(){
+                keys = read_pepper_file(path)?;
+            }
+        }
+
+        if settings.enabled && keys.is_empty(){
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no password pepper configured"))
+        }
+
+        return Ok(Peppers {
+            keys: keys,
+            enabled: settings.enabled
+        })
+    }
+
+    ///Pepper of new hashes, the one with the highest id. None when peppering is disabled.
+    pub fn current(&self) -> Option<(u32, &[u8])>{
+        if !self.enabled{
+            return None
+        }
+
+        return self.keys.iter().next_back().map(|(id, pepper)| (*id, pepper.as_slice()))
+    }
+
+    pub fn get(&self, id: u32) -> Option<&[u8]>{
+        return self.keys.get(&id).map(|pepper| pepper.as_slice())
+    }
+}
+
+
+fn parse_id(id: &str) -> io::Result<u32>{
+    return id.trim().parse::<u32>().ok().filter(|id| *id > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("pepper id {:?} is not a positive number", id)))
+}
+
+///Decode a hex encoded pepper.
+fn decode_pepper(encoded: &str) -> io::Result<Vec<u8>>{
+    let bytes = hex::decode(encoded.trim())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("pepper is not valid hex: {}", error)))?;
+
+    if bytes.len() < PEPPER_SIZE{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("pepper must be at least {} bytes", PEPPER_SIZE)))
+    }
+
+    return Ok(bytes)
+}
+
+///Read peppers from the key file, one `<id> <hex pepper>` pair per line.
+fn read_pepper_file(path: &Path) -> io::Result<BTreeMap<u32, Vec<u8>>>{
+    let mut keys: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate(){
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: malformed pepper entry", path.display(), number + 1));
+        let (id, encoded) = line.split_once(' ').ok_or_else(invalid)?;
+
+        if keys.insert(parse_id(id)?, decode_pepper(encoded)?).is_some(){
+            return Err(invalid())
+        }
+    }
+
+    return Ok(keys)
+}
+
+///Write a new owner-only key file holding one pepper with id 1.
+fn write_pepper_file(path: &Path, pepper: &[u8]) -> io::Result<()>{
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    writeln!(file, "# Password peppers, \"<id> <hex>\". The highest id peppers new hashes. Keep this file secret.")?;
+    writeln!(file, "1 {}", hex::encode(pepper))?;
+
+    return file.sync_all()
+}
@@ -0,0 +1,43 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use serde::Serialize;
+
+use crate::database::store::{Backend, UserStore};
+
+use super::{errors::AuthError, sessions::AuthenticatedSession, usernames::UsernameKeys};
+
+
+///Account details shown to their owner. The username is only known when stored for display.
+#[derive(Serialize)]
+pub struct Profile{
+    id: String,
+    username: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
+}
+
+///Handler that returns the account of the current session.
+pub async fn me<B: Backend>(auth: AuthenticatedSession<B>, database: web::Data<B>, keys: web::Data<UsernameKeys>) -> Result<HttpResponse, AuthError> {
+    let user_id = *auth.session.get_user_id();
+    let user = database.run(move |database_handler| Ok(database_handler.get_user_from_id(&user_id)?)).await;
+
+    match user{
+        Ok(Some(user)) => {
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json(Profile {
+                id: user.get_id().to_string(),
+                username: user.get_display_username().as_deref().and_then(|sealed| keys.open(user.get_id(), sealed)),
+                email: user.get_email().clone(),
+                email_verified: user.is_email_verified()
+            }))
+        },
+        Ok(None) => {
+            auth.cookie.purge();
+            return Err(AuthError::NoSession)
+        },
+        Err(error) => {
+            println!("Error while fetching user: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
@@ -1,28 +1,166 @@
+use std::{marker::PhantomData, str::FromStr};
+
+use actix_session::{Session as CookieSession, SessionExt};
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use rusqlite::Error;
 use uuid::Uuid;
 
-use crate::models::database_models::Session;
+use super::errors::AuthError;
+
+use crate::{config::server_config::{ServerConfig, SessionSettings}, database::store::{Backend, SessionStore}, models::database_models::Session, utils::time::unix_now};
 
 
-pub struct SessionManager{}
+///Outcome of checking a cookie against the session table.
+pub enum SessionCheck{
+    Valid(Session),
+    Expired,
+    Missing,
+}
+
+#[derive(Clone, Copy)]
+pub struct SessionManager{
+    absolute_timeout: i64,
+    idle_timeout: i64,
+    guest_ttl: i64
+}
 
 impl SessionManager{
-    ///Get a session manager.
-    pub fn new() -> Self{
-        SessionManager {}
+    ///Get a session manager using the configured timeouts.
+    pub fn new(settings: &SessionSettings) -> Self{
+        SessionManager {
+            absolute_timeout: settings.absolute_timeout_seconds(),
+            idle_timeout: settings.idle_timeout_seconds(),
+            guest_ttl: settings.guest_ttl_seconds()
+        }
     }
 
-    ///Create session for a specified user id. Session time is one hour.
+    ///Create session for a specified user id. Session time is the configured absolute timeout.
     pub fn create_session(&self, user_id: &Uuid) -> Session{
         let session_id = Uuid::new_v4();
+        let now = unix_now();
 
-        return Session::new(session_id, *user_id)
+        return Session::new(session_id, *user_id, now, now, now + self.absolute_timeout)
     }
-    
 
+
+    ///Create a session for a user that keeps an existing session id, e.g. when a guest registers.
+    pub fn adopt_session(&self, session_id: &Uuid, user_id: &Uuid) -> Session{
+        let now = unix_now();
+
+        return Session::new(*session_id, *user_id, now, now, now + self.absolute_timeout)
+    }
+
+    ///Create a session for a new guest id. Guest sessions last the configured guest ttl.
     pub fn guest_session(&self) -> Session{
         let user_id = Uuid::new_v4();
         let session_id = Uuid::new_v4();
+        let now = unix_now();
+
+        return Session::new(session_id, user_id, now, now, now + self.guest_ttl)
+    }
+
+    ///Create a new guest session for an existing guest id, lasting the configured guest ttl.
+    pub fn renew_guest_session(&self, guest_id: &Uuid) -> Session{
+        let session_id = Uuid::new_v4();
+        let now = unix_now();
+
+        return Session::new(session_id, *guest_id, now, now, now + self.guest_ttl)
+    }
+
+    ///Look up a session and enforce its timeouts.
+    ///Expired sessions are deleted, valid ones have their last activity updated.
+    pub fn check(&self, database_handler: &impl SessionStore, session_id: &Uuid, user_id: &Uuid) -> Result<SessionCheck, Error>{
+        let db_session = match database_handler.get_session_from_id(session_id)?{
+            Some(db_session) if db_session.get_user_id().eq(user_id) => db_session,
+            _ => return Ok(SessionCheck::Missing),
+        };
+
+        let now = unix_now();
+
+        if db_session.is_expired(now, self.idle_timeout){
+            database_handler.delete_session(session_id, user_id)?;
+            return Ok(SessionCheck::Expired)
+        }
+
+        database_handler.touch_session(session_id, now)?;
+
+        return Ok(SessionCheck::Valid(Session::new(
+            *db_session.get_id(),
+            *db_session.get_user_id(),
+            *db_session.get_created_at(),
+            now,
+            *db_session.get_expires_at()
+        )))
+    }
+}
+
+
+///Session id and user id stored in the cookie, if both are present and well formed.
+pub fn cookie_ids(session: &CookieSession) -> Option<(Uuid, Uuid)>{
+    let name = session.get::<String>("name").ok().flatten()?;
+    let value = session.get::<String>("value").ok().flatten()?;
+
+    match (Uuid::from_str(&name), Uuid::from_str(&value)){
+        (Ok(session_id), Ok(user_id)) => Some((session_id, user_id)),
+        _ => None,
+    }
+}
+
+
+///Extractor for handlers that require a logged in user.
+///Rejects the request with 401 and purges the cookie when the session is unknown or expired.
+///Sessions are looked up in the backend `B` the server was started with.
+pub struct AuthenticatedSession<B: Backend>{
+    pub session: Session,
+    pub cookie: CookieSession,
+    backend: PhantomData<B>
+}
+
+impl<B: Backend> FromRequest for AuthenticatedSession<B>{
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        return Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+async fn authenticate<B: Backend>(req: &HttpRequest) -> Result<AuthenticatedSession<B>, AuthError>{
+    let cookie = req.get_session();
+
+    let (config, database) = match (req.app_data::<web::Data<ServerConfig>>(), req.app_data::<web::Data<B>>()){
+        (Some(config), Some(database)) => (config, database),
+        _ => return Err(AuthError::Internal("server configuration missing")),
+    };
+
+    let (session_id, user_id) = match cookie_ids(&cookie){
+        Some(ids) => ids,
+        None => {
+            cookie.purge();
+            return Err(AuthError::NoSession)
+        },
+    };
+
+    let manager = SessionManager::new(&config.session);
+    let checked = database.run(move |database_handler| Ok(manager.check(database_handler, &session_id, &user_id)?)).await;
 
-        return Session::new(session_id, user_id)
+    match checked{
+        Ok(SessionCheck::Valid(session)) => {
+            return Ok(AuthenticatedSession { session: session, cookie: cookie, backend: PhantomData })
+        },
+        Ok(SessionCheck::Expired) => {
+            cookie.purge();
+            return Err(AuthError::SessionExpired)
+        },
+        Ok(SessionCheck::Missing) => {
+            cookie.purge();
+            return Err(AuthError::NoSession)
+        },
+        Err(error) => {
+            println!("Error while validating session: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
     }
 }
@@ -0,0 +1,538 @@
+use std::{collections::BTreeMap, env, fs, path::PathBuf};
+
+use actix_web::{body::MessageBody, cookie::{Cookie, CookieJar, Key}, dev::{ServiceFactory, ServiceRequest, ServiceResponse}, guard, http::{header::SET_COOKIE, StatusCode}, middleware::from_fn, test, web, App, Error, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{admin::lockouts::unlock, config::server_config::{Argon2Settings, CookieSettings, PepperSettings, ServerConfig}, cookie_handler, database::{memory::MemoryDatabase, store::{Backend, MailStore, UserStore}}, models::database_models::User, utils::{client_ip::TrustedProxies, time::{unix_now, SECONDS_PER_DAY}}};
+
+use super::{credentials::{guest_credentials, save_credentials, upgrade_guest, verify_credentials}, hasher::Hasher, key_ring::{reseal_session_cookie, KeyRing}, lockout::unlock_account, logout::logout, password::{change_password, forgot_password, reset_password}, pepper::Peppers, profile::me, usernames::{legacy_index, UsernameKeys}};
+
+const PASSWORD: &str = "Passw0rd!Passw0rd";
+const ADMIN_TOKEN: &str = "admin-token-admin-token-admin-token";
+
+
+///Configuration with fixed keys and cheap hashes. Accounts lock after three failed logins, without delays before.
+fn test_config() -> ServerConfig{
+    let mut config = ServerConfig::default();
+    config.cookie.key = Some("ab".repeat(64));
+    config.username.key = Some("cd".repeat(32));
+    config.pepper.keys = BTreeMap::from([(String::from("1"), "ef".repeat(32))]);
+    config.argon2.memory_kib = 1024;
+    config.argon2.iterations = 1;
+    config.argon2.parallelism = 1;
+    config.lockout.free_attempts = 10;
+    config.lockout.account_threshold = 3;
+    config.admin.token = Some(String::from(ADMIN_TOKEN));
+    return config
+}
+
+///The credential and session routes on an empty memory database.
+fn test_app(config: ServerConfig) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = Error, InitError = ()>>{
+    return test_app_on(config, web::Data::new(MemoryDatabase::default()))
+}
+
+///The credential and session routes on the given memory database.
+fn test_app_on(config: ServerConfig, database: web::Data<MemoryDatabase>) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = Error, InitError = ()>>{
+    let key_ring = KeyRing::load(&config.cookie).expect("cookie key");
+    let username_keys = UsernameKeys::load(&config.username).expect("username key");
+    let peppers = Peppers::load(&config.pepper).expect("pepper");
+    let cookie = cookie_handler(&config.cookie, key_ring.current());
+
+    return App::new()
+        .app_data(database)
+        .app_data(web::Data::new(username_keys))
+        .app_data(web::Data::new(peppers))
+        .app_data(web::Data::new(TrustedProxies::default()))
+        .app_data(web::Data::new(config))
+        .wrap(cookie)
+        .route("/sanitize", web::route().guard(guard::Post()).to(save_credentials::<MemoryDatabase>))
+        .route("/verify", web::route().guard(guard::Post()).to(verify_credentials::<MemoryDatabase>))
+        .route("/guest", web::route().guard(guard::Post()).to(guest_credentials::<MemoryDatabase>))
+        .route("/guest/upgrade", web::route().guard(guard::Post()).to(upgrade_guest::<MemoryDatabase>))
+        .route("/password/change", web::route().guard(guard::Post()).to(change_password::<MemoryDatabase>))
+        .route("/logout", web::route().guard(guard::Post()).to(logout::<MemoryDatabase>))
+        .route("/me", web::route().guard(guard::Get()).to(me::<MemoryDatabase>))
+        .route("/password/forgot", web::route().guard(guard::Post()).to(forgot_password::<MemoryDatabase>))
+        .route("/password/reset", web::route().guard(guard::Post()).to(reset_password::<MemoryDatabase>))
+        .route("/unlock-account", web::route().guard(guard::Get()).to(unlock_account::<MemoryDatabase>))
+        .route("/admin/lockouts/unlock", web::route().guard(guard::Post()).to(unlock::<MemoryDatabase>))
+}
+
+///An account created before the blind index, stored under the plain SHA-256 of its username.
+fn insert_legacy_user(config: &ServerConfig, database: &MemoryDatabase, username: &str){
+    let peppers = Peppers::load(&config.pepper).expect("pepper");
+    let hasher = Hasher::new(&config.argon2, &peppers);
+    let salt = hasher.random_salt();
+    let password = hasher.hash_password(PASSWORD, &salt).expect("hash");
+    let email = format!("{}@example.com", username);
+    let user = User::new(Uuid::new_v4(), legacy_index(username), password, 0, salt, Some(email), true, None, false);
+
+    database.with(|database_handler| Ok(database_handler.insert_user(user)?)).expect("insert user");
+}
+
+///Token of the last queued link starting with `link`.
+fn mailed_token(database: &MemoryDatabase, link: &str) -> String{
+    let mail = database.with(|database_handler| Ok(database_handler.claim_due_mail(unix_now(), unix_now() + 60, 100)?)).expect("outbox");
+
+    return mail.iter().rev()
+        .find_map(|mail| mail.get_body().split(link).nth(1))
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("mailed link")
+        .to_string()
+}
+
+fn credentials(username: &str, password: &str, email: &str) -> Value{
+    return json!({ "data": { "username": username, "password": password, "email": email } })
+}
+
+fn post(path: &str, body: &Value) -> test::TestRequest{
+    return test::TestRequest::post()
+        .uri(path)
+        .peer_addr("127.0.0.1:40000".parse().unwrap())
+        .set_json(body)
+}
+
+///Session cookie set by a response.
+fn session_cookie(response: &ServiceResponse<impl MessageBody>) -> Cookie<'static>{
+    return response.response().cookies().next().expect("session cookie").into_owned()
+}
+
+async fn error_code(response: ServiceResponse<impl MessageBody>) -> String{
+    let body: Value = test::read_body_json(response).await;
+    return body["code"].as_str().unwrap_or_default().to_string()
+}
+
+///Key file in the temp directory holding `(age in days, key)` pairs, removed when dropped.
+struct KeyFile(PathBuf);
+
+impl KeyFile{
+    fn new(name: &str, keys: &[(i64, &Key)]) -> Self{
+        let path = env::temp_dir().join(format!("almc-{}-{}", name, std::process::id()));
+        let lines: Vec<String> = keys.iter()
+            .map(|(days, key)| format!("{} {}", unix_now() - days * SECONDS_PER_DAY, hex::encode(key.master())))
+            .collect();
+
+        fs::write(&path, lines.join("\n")).expect("key file");
+        return KeyFile(path)
+    }
+
+    fn settings(&self) -> CookieSettings{
+        return CookieSettings { key: None, key_file: self.0.display().to_string(), ..CookieSettings::default() }
+    }
+
+    ///Hex encoded keys left in the file.
+    fn keys(&self) -> Vec<String>{
+        return fs::read_to_string(&self.0).expect("key file").lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once(' ').map(|(_, key)| key.to_string()))
+            .collect()
+    }
+}
+
+impl Drop for KeyFile{
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+///Peppers with the given ids, each a repeated byte.
+fn peppers(ids: &[u32]) -> Peppers{
+    let keys = ids.iter().map(|id| (id.to_string(), format!("{:02x}", id).repeat(32))).collect();
+    return Peppers::load(&PepperSettings { keys: keys, ..PepperSettings::default() }).expect("pepper")
+}
+
+///Cookie value sealed with `key`.
+fn seal(key: &Key, name: &str, value: &str) -> String{
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(Cookie::new(name.to_string(), value.to_string()));
+    return jar.get(name).expect("sealed cookie").value().to_string()
+}
+
+///Echo the session cookie as opened with the current key.
+async fn open_cookie(req: HttpRequest, ring: web::Data<KeyRing>, config: web::Data<ServerConfig>) -> HttpResponse{
+    let opened = req.cookie(&config.cookie.name)
+        .and_then(|cookie| CookieJar::new().private(ring.current()).decrypt(cookie));
+
+    match opened{
+        Some(cookie) => return HttpResponse::Ok().body(cookie.value().to_string()),
+        None => return HttpResponse::Unauthorized().finish(),
+    }
+}
+
+
+#[actix_web::test]
+async fn register_refuses_taken_username(){
+    let app = test::init_service(test_app(test_config())).await;
+
+    let response = test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "other@example.com")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "username_taken");
+
+    let response = test::call_service(&app, post("/sanitize", &credentials("bob", PASSWORD, "alice@example.com")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "email_taken");
+}
+
+#[actix_web::test]
+async fn login_starts_session_and_logout_ends_it(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", "wrong", "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(response).await, "invalid_credentials");
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["username"], "alice");
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/logout").cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    //The old cookie names a revoked session
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn guest_upgrade_keeps_session(){
+    let app = test::init_service(test_app(test_config())).await;
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest").to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, post("/guest/upgrade", &credentials("carol", PASSWORD, "carol@example.com")).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["username"], "carol");
+
+    let response = test::call_service(&app, post("/guest/upgrade", &credentials("dave", PASSWORD, "dave@example.com")).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "already_registered");
+}
+
+#[actix_web::test]
+async fn guest_ends_the_user_session(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    let user_cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest").cookie(user_cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    //The old cookie no longer names a session
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(user_cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn guest_upgrade_accepts_a_form(){
+    let app = test::init_service(test_app(test_config())).await;
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest").to_request()).await;
+    let cookie = session_cookie(&response);
+
+    let form = [("username", "erin"), ("password", PASSWORD), ("email", "erin@example.com")];
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest/upgrade").set_form(form).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = test::call_service(&app, post("/verify", &credentials("erin", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn repeated_failures_lock_the_account(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    for _ in 0..2{
+        let response = test::call_service(&app, post("/verify", &credentials("alice", "wrong", "")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", "wrong", "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+    assert!(response.headers().contains_key("retry-after"));
+
+    //The right password does not open a locked account
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+    assert_eq!(error_code(response).await, "account_locked");
+}
+
+#[actix_web::test]
+async fn wrong_old_passwords_lock_the_account(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    let cookie = session_cookie(&response);
+    let change = json!({ "data": { "old_password": "wrong", "new_password": "N3w!Passw0rd" } });
+
+    for _ in 0..2{
+        let response = test::call_service(&app, post("/password/change", &change).cookie(cookie.clone()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    let response = test::call_service(&app, post("/password/change", &change).cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    let change = json!({ "data": { "old_password": PASSWORD, "new_password": "N3w!Passw0rd" } });
+    let response = test::call_service(&app, post("/password/change", &change).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+}
+
+#[actix_web::test]
+async fn unlock_link_opens_a_legacy_account(){
+    let config = test_config();
+    let database = web::Data::new(MemoryDatabase::default());
+
+    insert_legacy_user(&config, &database, "erin");
+
+    let app = test::init_service(test_app_on(config, database.clone())).await;
+
+    for _ in 0..2{
+        let response = test::call_service(&app, post("/verify", &credentials("erin", "wrong", "")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let response = test::call_service(&app, post("/verify", &credentials("erin", "wrong", "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    //The lock mailed the owner a link
+    let token = mailed_token(&database, "/unlock-account?token=");
+
+    let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/unlock-account?token={}", token)).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test::call_service(&app, post("/verify", &credentials("erin", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn guest_logs_in_to_an_account(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest").to_request()).await;
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(cookie.clone()).to_request()).await;
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["username"], "alice");
+
+    //Logged in users still have to log out before logging in as someone else
+    test::call_service(&app, post("/sanitize", &credentials("bob", PASSWORD, "bob@example.com")).to_request()).await;
+    let response = test::call_service(&app, post("/verify", &credentials("bob", PASSWORD, "")).cookie(cookie).to_request()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn admin_unlocks_a_legacy_account(){
+    let config = test_config();
+    let database = web::Data::new(MemoryDatabase::default());
+    insert_legacy_user(&config, &database, "frank");
+
+    let app = test::init_service(test_app_on(config, database)).await;
+
+    for _ in 0..3{
+        test::call_service(&app, post("/verify", &credentials("frank", "wrong", "")).to_request()).await;
+    }
+
+    let response = test::call_service(&app, post("/verify", &credentials("frank", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    let response = test::call_service(&app, post("/admin/lockouts/unlock", &json!({ "username": "frank" }))
+        .insert_header(("Authorization", format!("Bearer {}", ADMIN_TOKEN)))
+        .to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test::call_service(&app, post("/verify", &credentials("frank", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn failures_from_one_address_back_off(){
+    let mut config = test_config();
+    config.lockout.ip_free_attempts = 2;
+    let app = test::init_service(test_app(config)).await;
+
+    //Every attempt names another account, only the address counter grows
+    for username in ["u1", "u2", "u3"]{
+        let response = test::call_service(&app, post("/verify", &credentials(username, "wrong", "")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let response = test::call_service(&app, post("/verify", &credentials("u4", "wrong", "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+}
+
+#[actix_web::test]
+async fn stale_session_does_not_block_login(){
+    //Sessions end as soon as they start
+    let mut config = test_config();
+    config.session.absolute_timeout_minutes = 0;
+    let app = test::init_service(test_app(config)).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+    test::call_service(&app, post("/sanitize", &credentials("bob", PASSWORD, "bob@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    let expired = session_cookie(&response);
+
+    let response = test::call_service(&app, post("/verify", &credentials("bob", PASSWORD, "")).cookie(expired).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn revoked_session_does_not_block_login(){
+    let app = test::init_service(test_app(test_config())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+    test::call_service(&app, post("/sanitize", &credentials("bob", PASSWORD, "bob@example.com")).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    let revoked = session_cookie(&response);
+    test::call_service(&app, test::TestRequest::post().uri("/logout").cookie(revoked.clone()).to_request()).await;
+
+    let response = test::call_service(&app, post("/verify", &credentials("bob", PASSWORD, "")).cookie(revoked).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = session_cookie(&response);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/me").cookie(cookie).to_request()).await;
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["username"], "bob");
+}
+
+#[actix_web::test]
+async fn password_reset_lifts_the_lock(){
+    let database = web::Data::new(MemoryDatabase::default());
+    let app = test::init_service(test_app_on(test_config(), database.clone())).await;
+    test::call_service(&app, post("/sanitize", &credentials("alice", PASSWORD, "alice@example.com")).to_request()).await;
+
+    for _ in 0..3{
+        test::call_service(&app, post("/verify", &credentials("alice", "wrong", "")).to_request()).await;
+    }
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", PASSWORD, "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    let response = test::call_service(&app, post("/password/forgot", &json!({ "data": { "email": "alice@example.com" } })).to_request()).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let token = mailed_token(&database, "/reset-password?token=");
+
+    let reset = json!({ "data": { "token": token, "password": "N3w!Passw0rd" } });
+    let response = test::call_service(&app, post("/password/reset", &reset).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test::call_service(&app, post("/verify", &credentials("alice", "N3w!Passw0rd", "")).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn key_ring_rotates_and_prunes_retired_keys(){
+    let (newest, retired, expired) = (Key::generate(), Key::generate(), Key::generate());
+
+    //The middle key retired a day ago and is still in its grace period, the oldest retired ten days ago
+    let file = KeyFile::new("ring-prune", &[(1, &newest), (10, &retired), (40, &expired)]);
+    let ring = KeyRing::load(&file.settings()).expect("key ring");
+    assert_eq!(ring.current().master(), newest.master());
+    assert_eq!(file.keys(), vec![hex::encode(newest.master()), hex::encode(retired.master())]);
+
+    //A key older than the rotation period is replaced and kept for its grace period
+    let file = KeyFile::new("ring-rotate", &[(40, &retired)]);
+    let ring = KeyRing::load(&file.settings()).expect("key ring");
+    assert_ne!(ring.current().master(), retired.master());
+    assert_eq!(file.keys(), vec![hex::encode(ring.current().master()), hex::encode(retired.master())]);
+}
+
+#[actix_web::test]
+async fn cookies_of_retired_keys_are_resealed(){
+    let (current, retired) = (Key::generate(), Key::generate());
+    let file = KeyFile::new("ring-reseal", &[(1, &current), (2, &retired)]);
+    let config = ServerConfig { cookie: file.settings(), ..test_config() };
+    let name = config.cookie.name.clone();
+
+    let ring = KeyRing::load(&config.cookie).expect("key ring");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(ring))
+            .app_data(web::Data::new(config))
+            .wrap(from_fn(reseal_session_cookie))
+            .route("/", web::get().to(open_cookie))
+    ).await;
+    let get = |value: String| test::TestRequest::get().uri("/").cookie(Cookie::new(name.clone(), value)).to_request();
+
+    //Opened with the current key, and sent back sealed with it
+    let response = test::call_service(&app, get(seal(&retired, &name, "session"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let resealed = session_cookie(&response);
+    assert_eq!(test::read_body(response).await, "session");
+
+    let response = test::call_service(&app, get(resealed.value().to_string())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(SET_COOKIE));
+
+    //Keys outside the ring open nothing
+    let response = test::call_service(&app, get(seal(&Key::generate(), &name, "session"))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn rotated_peppers_verify_old_hashes_and_ask_for_rehash(){
+    let settings = test_config().argon2;
+    let (first, rotated, replaced) = (peppers(&[1]), peppers(&[1, 2]), peppers(&[2]));
+
+    let hasher = Hasher::new(&settings, &first);
+    let old_hash = hasher.hash_password(PASSWORD, &hasher.random_salt()).expect("hash");
+    assert!(!hasher.needs_rehash(&old_hash));
+
+    //The newest pepper hashes, the old one still verifies
+    let hasher = Hasher::new(&settings, &rotated);
+    assert!(hasher.verify_password(PASSWORD, &old_hash));
+    assert!(!hasher.verify_password("wrong", &old_hash));
+    assert!(hasher.needs_rehash(&old_hash));
+
+    let new_hash = hasher.hash_password(PASSWORD, &hasher.random_salt()).expect("hash");
+    assert!(!hasher.needs_rehash(&new_hash));
+
+    //Hashes of a removed pepper no longer verify
+    let hasher = Hasher::new(&settings, &replaced);
+    assert!(hasher.verify_password(PASSWORD, &new_hash));
+    assert!(!hasher.verify_password(PASSWORD, &old_hash));
+}
+
+#[actix_web::test]
+async fn changed_costs_ask_for_rehash(){
+    let settings = test_config().argon2;
+    let peppers = peppers(&[1]);
+
+    let hasher = Hasher::new(&settings, &peppers);
+    let hash = hasher.hash_password(PASSWORD, &hasher.random_salt()).expect("hash");
+
+    let stronger = Argon2Settings { iterations: settings.iterations + 1, ..settings.clone() };
+    let hasher = Hasher::new(&stronger, &peppers);
+    assert!(hasher.verify_password(PASSWORD, &hash));
+    assert!(hasher.needs_rehash(&hash));
+
+    assert!(hasher.needs_rehash("not a phc string"));
+}
@@ -0,0 +1,163 @@
+use std::{fs, io::{self, Write}, path::Path};
+
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::server_config::UsernameSettings;
+
+///Size of the username secret in bytes.
+const KEY_SIZE: usize = 32;
+///Size of an AES-GCM nonce in bytes.
+const NONCE_SIZE: usize = 12;
+
+type HmacSha256 = Hmac<Sha256>;
+
+
+///Keys derived from the username secret.
+///Usernames are stored as a keyed blind index, lowercase hex, and optionally encrypted for display.
+pub struct UsernameKeys{
+    index_key: [u8; KEY_SIZE],
+    display_key: [u8; KEY_SIZE],
+    store_display: bool,
+}
+
+impl UsernameKeys{
+    ///Load the username secret described by the settings, generating the key file on first start.
+    pub fn load(settings: &UsernameSettings) -> io::Result<UsernameKeys>{
+        let secret = match &settings.key{
+            Some(encoded) => decode_key(encoded)?,
+            None => {
+                let path = Path::new(&settings.key_file);
+
+                if !path.exists(){
+                    println!("Generating new username key...");
+                    let mut secret = vec![0u8; KEY_SIZE];
+                    OsRng.fill_bytes(&mut secret);
+                    write_key_file(path, &secret)?;
+                }
+
+                read_key_file(path)?
+            },
+        };
+
+        return Ok(UsernameKeys {
+            index_key: derive(&secret, b"username index"),
+            display_key: derive(&secret, b"username display"),
+            store_display: settings.store_display
+        })
+    }
+
+    ///Blind index of a username, the value stored and looked up in `user.username`.
+    pub fn index(&self, username: &str) -> String{
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.index_key).expect("hmac takes keys of any size");
+        mac.update(username.as_bytes());
+
+        return hex::encode(mac.finalize().into_bytes())
+    }
+
+    ///Encrypt a username for display. The user id is bound to the ciphertext, so it cannot be moved to another row.
+    pub fn seal(&self, user_id: &Uuid, username: &str) -> Result<String, String>{
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        let payload = Payload { msg: username.as_bytes(), aad: user_id.as_bytes() };
+        let ciphertext = self.cipher().encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|error| error.to_string())?;
+
+        return Ok(format!("{}{}", hex::encode(nonce), hex::encode(ciphertext)))
+    }
+
+    ///Display username to store for a user, None when display names are not stored.
+    pub fn display(&self, user_id: &Uuid, username: &str) -> Option<String>{
+        if !self.store_display{
+            return None
+        }
+
+        match self.seal(user_id, username){
+            Ok(sealed) => return Some(sealed),
+            Err(error) => {
+                println!("Error while sealing display username: {}", error);
+                return None
+            },
+        }
+    }
+
+    ///Decrypt a display username sealed for `user_id`. None when it was sealed with another key or for another user.
+    pub fn open(&self, user_id: &Uuid, sealed: &str) -> Option<String>{
+        let bytes = hex::decode(sealed).ok().filter(|bytes| bytes.len() > NONCE_SIZE)?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+
+        let payload = Payload { msg: ciphertext, aad: user_id.as_bytes() };
+        let username = self.cipher().decrypt(Nonce::from_slice(nonce), payload).ok()?;
+
+        return String::from_utf8(username).ok()
+    }
+
+    fn cipher(&self) -> Aes256Gcm{
+        return Aes256Gcm::new(&self.display_key.into())
+    }
+}
+
+
+///Unkeyed index of accounts created before the blind index: uppercase hex SHA-256 of the username.
+///Such rows are rewritten to the blind index on their next successful login.
+pub fn legacy_index(username: &str) -> String{
+    return format!("{:X}", Sha256::digest(username.as_bytes()))
+}
+
+///Whether a stored username is still a legacy index. Blind indexes are lowercase.
+pub fn is_legacy_index(stored: &str) -> bool{
+    return stored.chars().any(|c| c.is_ascii_uppercase())
+}
+
+
+///Subkey for one purpose, so the index and display keys never coincide.
+fn derive(secret: &[u8], purpose: &[u8]) -> [u8; KEY_SIZE]{
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("hmac takes keys of any size");
+    mac.update(purpose);
+
+    return mac.finalize().into_bytes().into()
+}
+
+///Decode a hex encoded secret.
+fn decode_key(encoded: &str) -> io::Result<Vec<u8>>{
+    let bytes = hex::decode(encoded.trim())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("username key is not valid hex: {}", error)))?;
+
+    if bytes.len() < KEY_SIZE{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("username key must be at least {} bytes", KEY_SIZE)))
+    }
+
+    return Ok(bytes)
+}
+
+///Read the secret from the key file, skipping comment lines.
+fn read_key_file(path: &Path) -> io::Result<Vec<u8>>{
+    let contents = fs::read_to_string(path)?;
+
+    match contents.lines().map(|line| line.trim()).find(|line| !line.is_empty() && !line.starts_with('#')){
+        Some(line) => return decode_key(line),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no username key", path.display()))),
+    }
+}
+
+///Write the secret to a new owner-only key file.
+fn write_key_file(path: &Path, secret: &[u8]) -> io::Result<()>{
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    writeln!(file, "# Username index and display key. Keep this file secret, losing it locks every user out.")?;
+    writeln!(file, "{}", hex::encode(secret))?;
+
+    return file.sync_all()
+}
@@ -0,0 +1,102 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::Error;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{config::server_config::EmailSettings, database::store::{Backend, Store, TokenStore}, mail::outbox::enqueue, utils::time::unix_now};
+
+use super::errors::AuthError;
+
+///Purpose stored with email verification tokens.
+pub const EMAIL_VERIFICATION: &str = "email";
+const TOKEN_BYTES: usize = 32;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery{
+    token: String
+}
+
+///Normalize an email address for storage and comparison: trimmed and lowercased.
+///Returns None when the address is not plausibly valid.
+pub fn normalize_email(email: &str) -> Option<String>{
+    let email = email.trim().to_lowercase();
+
+    if email.len() > MAX_EMAIL_LENGTH || email.chars().any(|c| c.is_whitespace() || c.is_control()){
+        return None
+    }
+
+    match email.split_once('@'){
+        Some((local, domain)) if !local.is_empty() && !domain.contains('@') => {
+            let labels_valid = domain.split('.').all(|label| !label.is_empty());
+
+            if labels_valid && domain.contains('.'){
+                return Some(email)
+            }
+
+            return None
+        },
+        _ => return None,
+    }
+}
+
+///Generate a random single-use token. Returns the token and the hash stored in the database.
+pub fn new_token() -> (String, String){
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+
+    let token = hex::encode(bytes);
+    let token_hash = hash_token(&token);
+
+    return (token, token_hash)
+}
+
+///Hash of a token as stored in the database.
+pub fn hash_token(token: &str) -> String{
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+
+    return hex::encode(hasher.finalize())
+}
+
+///Store a verification token for the user and queue a mail with the confirmation link.
+pub fn issue_email_verification(database_handler: &impl Store, settings: &EmailSettings, user_id: &Uuid, email: &str) -> Result<(), Error>{
+    let (token, token_hash) = new_token();
+    let now = unix_now();
+
+    database_handler.insert_verification_token(&token_hash, user_id, EMAIL_VERIFICATION, now, now + settings.verification_ttl_seconds())?;
+
+    let link = format!("{}/verify-email?token={}", settings.public_url.trim_end_matches('/'), token);
+    let body = format!(
+        "Confirm your email address by opening the link below.\n\n{}\n\nThe link expires in {} hour(s).",
+        link, settings.verification_ttl_hours
+    );
+
+    enqueue(database_handler, email, "Confirm your email address", &body)?;
+    return Ok(())
+}
+
+
+///Handler that confirms an email address with the token from the verification link.
+pub async fn verify_email<B: Backend>(database: web::Data<B>, query: web::Query<VerifyEmailQuery>) -> Result<HttpResponse, AuthError> {
+    let token_hash = hash_token(query.token.trim());
+
+    match database.run(move |database_handler| Ok(database_handler.confirm_email(&token_hash, EMAIL_VERIFICATION, unix_now())?)).await{
+        Ok(Some(user_id)) => {
+            println!("Email verified for user {:?}", user_id);
+            return Ok(HttpResponse::Ok()
+            .status(StatusCode::OK)
+            .json("Status : Email verified."))
+        },
+        Ok(None) => {
+            return Err(AuthError::InvalidToken)
+        },
+        Err(error) => {
+            println!("Error while verifying email: {:?}", error);
+            return Err(AuthError::Database(error))
+        },
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+
+///Command line flags. Flags take precedence over environment variables and the configuration file.
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "Authentication server.")]
+pub struct Cli{
+    ///Path to the TOML configuration file.
+    #[arg(short, long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    ///Listener address. May be repeated, replaces the configured listeners.
+    #[arg(short, long, value_name = "ADDRESS")]
+    pub bind: Vec<String>,
+
+    ///Path to the SQLite database file.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub database: Option<String>,
+
+    ///Override any configuration key, e.g. `--set cookie.secure=false`. May be repeated.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub overrides: Vec<String>,
+
+    ///Run a command instead of the server.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum Command{
+    ///Inspect or upgrade the database schema.
+    Migrate{
+        #[command(subcommand)]
+        action: MigrateAction
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum MigrateAction{
+    ///List the migrations and whether they are applied.
+    Status,
+    ///Apply every pending migration.
+    Up,
+}
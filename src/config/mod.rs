@@ -0,0 +1,5 @@
+pub mod cli;
+pub mod server_config;
+
+#[cfg(test)]
+mod tests;
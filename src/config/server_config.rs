@@ -0,0 +1,993 @@
+use std::{collections::BTreeMap, env, fmt, fs, net::ToSocketAddrs, path::{Path, PathBuf}};
+
+use actix_web::{cookie::SameSite, http::uri::Authority};
+use argon2::{Algorithm, Params};
+use lettre::message::Mailbox;
+use serde::Deserialize;
+use tokio_cron_scheduler::Job;
+use toml::{Table, Value};
+
+use crate::utils::client_ip::TrustedProxies;
+
+use super::cli::{Cli, Command};
+
+///Prefix of environment variables read as configuration. Nested keys are separated by `__`, e.g. `ALMC_DATABASE__PATH`.
+pub const ENV_PREFIX: &str = "ALMC_";
+const ENV_SEPARATOR: &str = "__";
+///Environment variable naming the configuration file. Not treated as a configuration key.
+const ENV_CONFIG_FILE: &str = "ALMC_CONFIG";
+///Configuration file read when none is given. A missing default file is not an error.
+const DEFAULT_CONFIG_PATH: &str = "./server.toml";
+
+
+///Error raised while loading or validating the server configuration.
+#[derive(Debug)]
+pub enum ConfigError{
+    Io{ path: PathBuf, source: std::io::Error },
+    Parse{ origin: String, message: String },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ConfigError::Io { path, source } => write!(f, "cannot read {}: {}", path.display(), source),
+            ConfigError::Parse { origin, message } => write!(f, "cannot parse {}: {}", origin, message),
+            ConfigError::Invalid(problems) => {
+                write!(f, "{} invalid setting(s):", problems.len())?;
+                for problem in problems{
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError{}
+
+
+///Typed server configuration.
+///Loaded from a TOML file, environment variables and command line flags, in increasing order of precedence.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig{
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub cookie: CookieSettings,
+    pub username: UsernameSettings,
+    pub session: SessionSettings,
+    pub argon2: Argon2Settings,
+    pub pepper: PepperSettings,
+    pub maintainer: MaintainerSettings,
+    pub admin: AdminSettings,
+    pub password_policy: PasswordPolicy,
+    pub email: EmailSettings,
+    pub mail: MailSettings,
+    pub lockout: LockoutSettings,
+    pub rate_limit: RateLimitSettings,
+}
+
+///Addresses the http server binds to, and the certificate served by tls listeners.
+///Requests from `trusted_proxies` (addresses or CIDR ranges) are attributed to the client named in `X-Forwarded-For`.
+///Request bodies larger than `max_body_bytes` are refused.
+///Plain http requests are redirected to `public_host`, or to the host in their Host header when it is unset.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings{
+    pub listeners: Vec<ListenerSettings>,
+    pub tls: Option<TlsSettings>,
+    pub public_host: Option<String>,
+    pub trusted_proxies: Vec<String>,
+    pub max_body_bytes: usize,
+}
+
+impl Default for ServerSettings{
+    fn default() -> Self {
+        ServerSettings {
+            listeners: vec![ListenerSettings { address: String::from("127.0.0.1:8081"), tls: false, redirect_to_https: false }],
+            tls: None,
+            public_host: None,
+            trusted_proxies: vec![],
+            max_body_bytes: 16384
+        }
+    }
+}
+
+///A listener serves the application over http or https,
+///or only redirects plain http requests to the first https listener.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings{
+    pub address: String,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub redirect_to_https: bool,
+}
+
+///PEM encoded certificate chain and private key. Reloaded from disk on SIGHUP.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings{
+    pub certificate: String,
+    pub private_key: String,
+}
+
+///Database file and connection pool. Queries run on at most `blocking_threads` threads per worker,
+///waiting up to `busy_timeout_ms` for a connection or a lock held by another writer.
+///Pending schema migrations are applied at startup unless `migrate_on_start` is off.
+///The memory backend ignores the rest and keeps all data in the process, lost on exit.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings{
+    pub backend: DatabaseBackend,
+    pub path: String,
+    pub pool_size: u32,
+    pub blocking_threads: usize,
+    pub busy_timeout_ms: u64,
+    pub statement_cache_size: usize,
+    pub migrate_on_start: bool,
+}
+
+impl Default for DatabaseSettings{
+    fn default() -> Self {
+        DatabaseSettings {
+            backend: DatabaseBackend::Sqlite,
+            path: String::from("./user_database.db3"),
+            pool_size: 8,
+            blocking_threads: 16,
+            busy_timeout_ms: 5000,
+            statement_cache_size: 64,
+            migrate_on_start: true
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend{
+    Sqlite,
+    Memory,
+}
+
+///Session cookie attributes and sealing keys.
+///`key` is a hex encoded 64 byte master key. When absent, keys are kept in `key_file` and rotated
+///at startup every `key_rotation_days` (0 disables rotation). Retired keys still open cookies for `key_grace_days`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieSettings{
+    pub name: String,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSitePolicy,
+    pub key: Option<String>,
+    pub key_file: String,
+    pub key_rotation_days: u64,
+    pub key_grace_days: u64,
+}
+
+impl Default for CookieSettings{
+    fn default() -> Self {
+        CookieSettings {
+            name: String::from("almc-tech"),
+            secure: true,
+            http_only: true,
+            same_site: SameSitePolicy::Strict,
+            key: None,
+            key_file: String::from("./session_keys"),
+            key_rotation_days: 30,
+            key_grace_days: 7
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy{
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<SameSitePolicy> for SameSite{
+    fn from(policy: SameSitePolicy) -> Self {
+        match policy{
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        }
+    }
+}
+
+///Secret keying the username blind index and encrypting display usernames.
+///A `key` given in the configuration replaces the key file, which is generated on first start.
+///With `store_display` set, the username is stored encrypted next to its index so it can be shown back.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameSettings{
+    pub key: Option<String>,
+    pub key_file: String,
+    pub store_display: bool,
+}
+
+impl Default for UsernameSettings{
+    fn default() -> Self {
+        UsernameSettings {
+            key: None,
+            key_file: String::from("./username_key"),
+            store_display: true
+        }
+    }
+}
+
+///Server side session lifetime. A session ends `absolute_timeout_minutes` after login,
+///or earlier when no authenticated request arrives for `idle_timeout_minutes`.
+///Guests expire `guest_ttl_hours` after their last visit and are removed by the guest cleanup job.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings{
+    pub absolute_timeout_minutes: u64,
+    pub idle_timeout_minutes: u64,
+    pub guest_ttl_hours: u64,
+}
+
+impl Default for SessionSettings{
+    fn default() -> Self {
+        SessionSettings {
+            absolute_timeout_minutes: 12 * 60,
+            idle_timeout_minutes: 60,
+            guest_ttl_hours: 24
+        }
+    }
+}
+
+impl SessionSettings{
+    pub fn absolute_timeout_seconds(&self) -> i64{
+        return self.absolute_timeout_minutes as i64 * 60
+    }
+
+    pub fn idle_timeout_seconds(&self) -> i64{
+        return self.idle_timeout_minutes as i64 * 60
+    }
+
+    pub fn guest_ttl_seconds(&self) -> i64{
+        return self.guest_ttl_hours as i64 * 60 * 60
+    }
+}
+
+///Argon2 variant and cost parameters of new password hashes.
+///Stored hashes made with other settings are replaced on the next successful login.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Settings{
+    pub variant: Argon2Variant,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Settings{
+    fn default() -> Self {
+        Argon2Settings {
+            variant: Argon2Variant::Argon2id,
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Argon2Variant{
+    Argon2id,
+    Argon2i,
+    Argon2d,
+}
+
+impl From<Argon2Variant> for Algorithm{
+    fn from(variant: Argon2Variant) -> Self {
+        match variant{
+            Argon2Variant::Argon2id => Algorithm::Argon2id,
+            Argon2Variant::Argon2i => Algorithm::Argon2i,
+            Argon2Variant::Argon2d => Algorithm::Argon2d,
+        }
+    }
+}
+
+impl Argon2Settings{
+    ///Argon2 parameters described by the settings.
+    pub fn params(&self) -> Result<Params, argon2::Error>{
+        return Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+///Server side secrets mixed into every password hash, so the database alone does not allow offline cracking.
+///Peppers are numbered. The highest id peppers new hashes and older ones keep verifying until rehashed on login.
+///Peppers listed in `keys` (also as `ALMC_PEPPER__KEYS__<id>`) replace the key file, which is generated on first start.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PepperSettings{
+    pub enabled: bool,
+    pub keys: BTreeMap<String, String>,
+    pub key_file: String,
+}
+
+impl Default for PepperSettings{
+    fn default() -> Self {
+        PepperSettings {
+            enabled: true,
+            keys: BTreeMap::new(),
+            key_file: String::from("./pepper_keys")
+        }
+    }
+}
+
+///Maintenance jobs run by the maintainer.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintainerSettings{
+    pub guest_cleanup: JobSettings,
+    pub mail_delivery: JobSettings,
+    pub legacy_salts: JobSettings,
+}
+
+impl Default for MaintainerSettings{
+    fn default() -> Self {
+        MaintainerSettings {
+            guest_cleanup: JobSettings::default(),
+            //Failed messages are retried by the outbox itself, not by rerunning the job
+            mail_delivery: JobSettings {
+                schedule: String::from("0 * * * * *"),
+                timeout_seconds: 120,
+                max_retries: 0,
+                retry_backoff_seconds: 30
+            },
+            legacy_salts: JobSettings {
+                schedule: String::from("0 30 3 * * *"),
+                ..JobSettings::default()
+            }
+        }
+    }
+}
+
+impl MaintainerSettings{
+    ///Every job with its configuration key.
+    pub fn jobs(&self) -> Vec<(&'static str, &JobSettings)>{
+        return vec![
+            ("guest_cleanup", &self.guest_cleanup),
+            ("mail_delivery", &self.mail_delivery),
+            ("legacy_salts", &self.legacy_salts),
+        ]
+    }
+}
+
+///Schedule and failure handling of one maintenance job.
+///Cron expressions include a leading seconds field. Each attempt is limited to `timeout_seconds`,
+///and failed attempts are retried up to `max_retries` times, doubling `retry_backoff_seconds` after each.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobSettings{
+    pub schedule: String,
+    pub timeout_seconds: u64,
+    pub max_retries: u32,
+    pub retry_backoff_seconds: u64,
+}
+
+impl Default for JobSettings{
+    fn default() -> Self {
+        JobSettings {
+            schedule: String::from("0 0 0 * * *"),
+            timeout_seconds: 300,
+            max_retries: 2,
+            retry_backoff_seconds: 30
+        }
+    }
+}
+
+///Administrative endpoints, disabled unless a bearer token is configured.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings{
+    pub token: Option<String>,
+}
+
+///Rules a password must satisfy on registration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicy{
+    pub min_length: usize,
+    pub require_digit: bool,
+    pub require_letter: bool,
+    pub require_special: bool,
+}
+
+impl Default for PasswordPolicy{
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            require_digit: true,
+            require_letter: true,
+            require_special: true
+        }
+    }
+}
+
+
+///Email address verification.
+///Links sent to users point at `public_url`, password reset links at its `/reset-password` page. Logins are refused until the address is confirmed when `require_verification` is set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailSettings{
+    pub require_verification: bool,
+    pub verification_ttl_hours: u64,
+    pub reset_ttl_minutes: u64,
+    pub public_url: String,
+}
+
+impl Default for EmailSettings{
+    fn default() -> Self {
+        EmailSettings {
+            require_verification: false,
+            verification_ttl_hours: 24,
+            reset_ttl_minutes: 30,
+            public_url: String::from("http://localhost:8080")
+        }
+    }
+}
+
+impl EmailSettings{
+    pub fn verification_ttl_seconds(&self) -> i64{
+        return self.verification_ttl_hours as i64 * 60 * 60
+    }
+
+    pub fn reset_ttl_seconds(&self) -> i64{
+        return self.reset_ttl_minutes as i64 * 60
+    }
+}
+
+
+///Outbound mail. Messages are queued in the outbox table and handed to `transport` by the mail delivery job.
+///A message that cannot be delivered is retried up to `max_attempts` times, doubling `retry_backoff_seconds` after each attempt.
+///The stdout transport prints only headers unless `stdout_show_body` is set, since bodies hold tokens.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailSettings{
+    pub transport: MailTransport,
+    pub from: String,
+    pub outbox_dir: String,
+    pub stdout_show_body: bool,
+    pub max_attempts: u32,
+    pub retry_backoff_seconds: u64,
+    pub smtp: SmtpSettings,
+}
+
+impl Default for MailSettings{
+    fn default() -> Self {
+        MailSettings {
+            transport: MailTransport::Stdout,
+            from: String::from("no-reply@localhost"),
+            outbox_dir: String::from("./outbox"),
+            stdout_show_body: false,
+            max_attempts: 5,
+            retry_backoff_seconds: 60,
+            smtp: SmtpSettings::default()
+        }
+    }
+}
+
+///Where outgoing messages are delivered.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport{
+    ///Print messages to standard output.
+    Stdout,
+    ///Write each message to `outbox_dir` as an `.eml` file.
+    File,
+    ///Relay messages through an SMTP server.
+    Smtp,
+}
+
+///SMTP relay. The port defaults to 587 for starttls, 465 for tls and 25 without encryption.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSettings{
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout_seconds: u64,
+}
+
+impl Default for SmtpSettings{
+    fn default() -> Self {
+        SmtpSettings {
+            host: String::new(),
+            port: None,
+            security: SmtpSecurity::Starttls,
+            username: None,
+            password: None,
+            timeout_seconds: 30
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity{
+    Starttls,
+    Tls,
+    None,
+}
+
+
+///Throttling of failed logins, counted per account and per client address.
+///After `free_attempts` failures of an account every attempt waits `base_delay_seconds`, doubled per further failure up to `max_delay_seconds`.
+///Addresses back off the same way after `ip_free_attempts`, set higher since many users may share one.
+///An account is locked for `lockout_minutes` after `account_threshold` failures, an address after `ip_threshold`.
+///Failures older than `window_minutes` are forgotten.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutSettings{
+    pub enabled: bool,
+    pub free_attempts: u32,
+    pub ip_free_attempts: u32,
+    pub base_delay_seconds: u64,
+    pub max_delay_seconds: u64,
+    pub account_threshold: u32,
+    pub ip_threshold: u32,
+    pub lockout_minutes: u64,
+    pub window_minutes: u64,
+}
+
+impl Default for LockoutSettings{
+    fn default() -> Self {
+        LockoutSettings {
+            enabled: true,
+            free_attempts: 3,
+            ip_free_attempts: 10,
+            base_delay_seconds: 1,
+            max_delay_seconds: 300,
+            account_threshold: 10,
+            ip_threshold: 50,
+            lockout_minutes: 15,
+            window_minutes: 60
+        }
+    }
+}
+
+impl LockoutSettings{
+    pub fn lockout_seconds(&self) -> i64{
+        return self.lockout_minutes as i64 * 60
+    }
+
+    pub fn window_seconds(&self) -> i64{
+        return self.window_minutes as i64 * 60
+    }
+}
+
+
+///Token bucket limits per route, keyed by client address.
+///Each route in `routes` is named by the path of the resource it limits, see `RateLimiter::limit`. A bucket holds `capacity` requests and regains `refill_per_minute` of them per minute.
+///The sqlite store shares buckets between instances using the same database.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings{
+    pub enabled: bool,
+    pub store: RateLimitStoreKind,
+    pub routes: BTreeMap<String, BucketSettings>,
+}
+
+impl Default for RateLimitSettings{
+    fn default() -> Self {
+        let routes = [
+            ("/guest", 10, 10),
+            ("/guest/upgrade", 5, 5),
+            ("/sanitize", 5, 5),
+            ("/verify", 20, 20),
+            ("/password/forgot", 5, 5),
+            ("/password/reset", 10, 10),
+            ("/password/change", 5, 5),
+            ("/username-available", 30, 30),
+            ("/verify-email", 10, 10),
+            ("/unlock-account", 10, 10),
+            ("/logout", 20, 20),
+            ("/logout-all", 5, 5),
+        ];
+
+        RateLimitSettings {
+            enabled: true,
+            store: RateLimitStoreKind::Memory,
+            routes: routes.iter()
+                .map(|(path, capacity, refill)| (path.to_string(), BucketSettings { capacity: *capacity, refill_per_minute: *refill }))
+                .collect()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind{
+    Memory,
+    Sqlite,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct BucketSettings{
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+
+impl ServerConfig{
+    ///Load configuration from the file, environment and command line flags, then validate it.
+    pub fn load(cli: &Cli) -> Result<ServerConfig, ConfigError>{
+        let mut table = read_file(cli)?;
+        apply_env(&mut table)?;
+        apply_cli(&mut table, cli)?;
+
+        let config: ServerConfig = Value::Table(table).try_into()
+            .map_err(|error: toml::de::Error| ConfigError::Parse {
+                origin: String::from("configuration"),
+                message: error.message().to_string()
+            })?;
+
+        //Migrations only touch the database, the rest need not be usable yet
+        match cli.command{
+            Some(Command::Migrate { .. }) => config.validate_database()?,
+            None => config.validate()?,
+        }
+
+        return Ok(config)
+    }
+
+    ///Check every setting, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError>{
+        let mut problems: Vec<String> = vec![];
+
+        if self.server.listeners.is_empty(){
+            problems.push(String::from("server.listeners: at least one listener is required"));
+        }
+
+        for listener in &self.server.listeners{
+            if let Err(error) = listener.address.to_socket_addrs(){
+                problems.push(format!("server.listeners: invalid address {:?}: {}", listener.address, error));
+            }
+
+            if listener.tls && listener.redirect_to_https{
+                problems.push(format!("server.listeners: {:?} cannot both serve tls and redirect to https", listener.address));
+            }
+        }
+
+        if let Err(entry) = TrustedProxies::parse(&self.server.trusted_proxies){
+            problems.push(format!("server.trusted_proxies: {:?} is not an address or CIDR range", entry));
+        }
+
+        if self.server.max_body_bytes == 0{
+            problems.push(String::from("server.max_body_bytes: must be at least 1"));
+        }
+
+        let serves_tls = self.server.listeners.iter().any(|listener| listener.tls);
+
+        if self.server.listeners.iter().any(|listener| listener.redirect_to_https) && !serves_tls{
+            problems.push(String::from("server.listeners: redirect_to_https requires a tls listener"));
+        }
+
+        if let Some(host) = &self.server.public_host{
+            let valid = !host.contains('@') && host.parse::<Authority>().is_ok_and(|authority| authority.port().is_none());
+
+            if !valid{
+                problems.push(format!("server.public_host: {:?} must be a host name or address without a port", host));
+            }
+        }
+
+        match &self.server.tls{
+            Some(tls) => {
+                for (key, path) in [("certificate", &tls.certificate), ("private_key", &tls.private_key)]{
+                    if !Path::new(path).is_file(){
+                        problems.push(format!("server.tls.{}: file {:?} does not exist", key, path));
+                    }
+                }
+            },
+            None => {
+                if serves_tls{
+                    problems.push(String::from("server.tls: certificate and private_key are required by tls listeners"));
+                }
+            },
+        }
+
+        problems.extend(self.database.problems());
+
+        if self.cookie.name.is_empty() || !self.cookie.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)){
+            problems.push(format!("cookie.name: {:?} is not a valid cookie name", self.cookie.name));
+        }
+
+        if self.cookie.same_site == SameSitePolicy::None && !self.cookie.secure{
+            problems.push(String::from("cookie.same_site: \"none\" requires cookie.secure = true"));
+        }
+
+        match &self.cookie.key{
+            Some(key) => {
+                if hex::decode(key.trim()).map_or(true, |bytes| bytes.len() < 64){
+                    problems.push(String::from("cookie.key: must be at least 64 hex encoded bytes"));
+                }
+            },
+            None => {
+                if self.cookie.key_file.trim().is_empty(){
+                    problems.push(String::from("cookie.key_file: must not be empty when cookie.key is not set"));
+                }
+            },
+        }
+
+        match &self.username.key{
+            Some(key) => {
+                if hex::decode(key.trim()).map_or(true, |bytes| bytes.len() < 32){
+                    problems.push(String::from("username.key: must be at least 32 hex encoded bytes"));
+                }
+            },
+            None => {
+                if self.username.key_file.trim().is_empty(){
+                    problems.push(String::from("username.key_file: must not be empty when username.key is not set"));
+                }
+            },
+        }
+
+        if self.session.absolute_timeout_minutes == 0 || self.session.idle_timeout_minutes == 0{
+            problems.push(String::from("session: timeouts must be at least one minute"));
+        }
+        else if self.session.idle_timeout_minutes > self.session.absolute_timeout_minutes{
+            problems.push(String::from("session.idle_timeout_minutes: must not exceed absolute_timeout_minutes"));
+        }
+
+        if self.session.guest_ttl_hours == 0{
+            problems.push(String::from("session.guest_ttl_hours: must be at least one hour"));
+        }
+
+        for (id, key) in &self.pepper.keys{
+            if id.parse::<u32>().map_or(true, |id| id == 0){
+                problems.push(format!("pepper.keys: id {:?} must be a positive number", id));
+            }
+
+            if hex::decode(key.trim()).map_or(true, |bytes| bytes.len() < 32){
+                problems.push(format!("pepper.keys.{}: must be at least 32 hex encoded bytes", id));
+            }
+        }
+
+        if self.pepper.keys.is_empty() && self.pepper.key_file.trim().is_empty(){
+            problems.push(String::from("pepper.key_file: must not be empty when pepper.keys is not set"));
+        }
+
+        if let Err(error) = self.argon2.params(){
+            problems.push(format!("argon2: {}", error));
+        }
+
+        for (name, job) in self.maintainer.jobs(){
+            if let Err(error) = Job::new(&job.schedule, |_, _| {}){
+                problems.push(format!("maintainer.{}.schedule: {:?} is not a valid cron expression: {}", name, job.schedule, error));
+            }
+
+            if job.timeout_seconds == 0{
+                problems.push(format!("maintainer.{}.timeout_seconds: must be at least 1", name));
+            }
+        }
+
+        if self.admin.token.as_ref().is_some_and(|token| token.len() < 32){
+            problems.push(String::from("admin.token: must be at least 32 characters"));
+        }
+
+        if self.password_policy.min_length == 0{
+            problems.push(String::from("password_policy.min_length: must be at least 1"));
+        }
+
+        if self.email.verification_ttl_hours == 0{
+            problems.push(String::from("email.verification_ttl_hours: must be at least one hour"));
+        }
+
+        if self.email.reset_ttl_minutes == 0{
+            problems.push(String::from("email.reset_ttl_minutes: must be at least one minute"));
+        }
+
+        if !(self.email.public_url.starts_with("http://") || self.email.public_url.starts_with("https://")){
+            problems.push(format!("email.public_url: {:?} must start with http:// or https://", self.email.public_url));
+        }
+
+        if self.mail.from.parse::<Mailbox>().is_err(){
+            problems.push(format!("mail.from: {:?} is not a valid mailbox", self.mail.from));
+        }
+
+        if self.mail.max_attempts == 0{
+            problems.push(String::from("mail.max_attempts: must be at least 1"));
+        }
+
+        match self.mail.transport{
+            MailTransport::File if self.mail.outbox_dir.trim().is_empty() => {
+                problems.push(String::from("mail.outbox_dir: must not be empty for the file transport"));
+            },
+            MailTransport::Smtp => {
+                if self.mail.smtp.host.trim().is_empty(){
+                    problems.push(String::from("mail.smtp.host: required by the smtp transport"));
+                }
+
+                if self.mail.smtp.username.is_some() != self.mail.smtp.password.is_some(){
+                    problems.push(String::from("mail.smtp: username and password must be set together"));
+                }
+
+                if self.mail.smtp.timeout_seconds == 0{
+                    problems.push(String::from("mail.smtp.timeout_seconds: must be at least 1"));
+                }
+            },
+            _ => {},
+        }
+
+        if self.lockout.account_threshold == 0 || self.lockout.ip_threshold == 0{
+            problems.push(String::from("lockout: thresholds must be at least 1"));
+        }
+
+        if self.lockout.lockout_minutes == 0 || self.lockout.window_minutes == 0{
+            problems.push(String::from("lockout: lockout_minutes and window_minutes must be at least 1"));
+        }
+
+        if self.lockout.base_delay_seconds > self.lockout.max_delay_seconds{
+            problems.push(String::from("lockout.base_delay_seconds: must not exceed max_delay_seconds"));
+        }
+
+        for (path, bucket) in &self.rate_limit.routes{
+            if !path.starts_with('/'){
+                problems.push(format!("rate_limit.routes: {:?} must be a path starting with /", path));
+            }
+
+            if bucket.capacity == 0 || bucket.refill_per_minute == 0{
+                problems.push(format!("rate_limit.routes.{:?}: capacity and refill_per_minute must be at least 1", path));
+            }
+        }
+
+        if problems.is_empty(){
+            return Ok(())
+        }
+
+        return Err(ConfigError::Invalid(problems))
+    }
+
+    ///Check only the database settings, for commands that do not start the server.
+    pub fn validate_database(&self) -> Result<(), ConfigError>{
+        let problems = self.database.problems();
+
+        if problems.is_empty(){
+            return Ok(())
+        }
+
+        return Err(ConfigError::Invalid(problems))
+    }
+}
+
+impl DatabaseSettings{
+    fn problems(&self) -> Vec<String>{
+        let mut problems: Vec<String> = vec![];
+
+        if self.path.trim().is_empty(){
+            problems.push(String::from("database.path: must not be empty"));
+        }
+        else if let Some(parent) = Path::new(&self.path).parent(){
+            if !parent.as_os_str().is_empty() && !parent.is_dir(){
+                problems.push(format!("database.path: directory {} does not exist", parent.display()));
+            }
+        }
+
+        if self.pool_size == 0 || self.blocking_threads == 0{
+            problems.push(String::from("database: pool_size and blocking_threads must be at least 1"));
+        }
+
+        return problems
+    }
+}
+
+
+///Read the configuration file. The default path is optional, an explicitly given one is not.
+fn read_file(cli: &Cli) -> Result<Table, ConfigError>{
+    let (path, required) = match (&cli.config, env::var_os(ENV_CONFIG_FILE)){
+        (Some(path), _) => (path.clone(), true),
+        (None, Some(path)) => (PathBuf::from(path), true),
+        (None, None) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+
+    if !required && !path.exists(){
+        return Ok(Table::new())
+    }
+
+    let contents = fs::read_to_string(&path)
+        .map_err(|error| ConfigError::Io { path: path.clone(), source: error })?;
+
+    return contents.parse::<Table>()
+        .map_err(|error| ConfigError::Parse {
+            origin: path.display().to_string(),
+            message: error.message().to_string()
+        })
+}
+
+///Overlay `ALMC_SECTION__KEY` environment variables.
+fn apply_env(table: &mut Table) -> Result<(), ConfigError>{
+    for (name, raw) in env::vars(){
+        if name == ENV_CONFIG_FILE{
+            continue;
+        }
+
+        if let Some(key) = name.strip_prefix(ENV_PREFIX){
+            let path: Vec<String> = key.to_lowercase().split(ENV_SEPARATOR).map(String::from).collect();
+            set_path(table, &path, parse_value(&raw), &name)?;
+        }
+    }
+
+    return Ok(())
+}
+
+///Overlay command line flags.
+fn apply_cli(table: &mut Table, cli: &Cli) -> Result<(), ConfigError>{
+    if !cli.bind.is_empty(){
+        let listeners = cli.bind.iter()
+            .map(|address| {
+                let mut listener = Table::new();
+                listener.insert(String::from("address"), Value::String(address.clone()));
+                Value::Table(listener)
+            })
+            .collect();
+
+        set_path(table, &[String::from("server"), String::from("listeners")], Value::Array(listeners), "--bind")?;
+    }
+
+    if let Some(database) = &cli.database{
+        set_path(table, &[String::from("database"), String::from("path")], Value::String(database.clone()), "--database")?;
+    }
+
+    for entry in &cli.overrides{
+        match entry.split_once('='){
+            Some((key, raw)) => {
+                let path: Vec<String> = key.trim().split('.').map(String::from).collect();
+                set_path(table, &path, parse_value(raw.trim()), "--set")?;
+            },
+            None => {
+                return Err(ConfigError::Parse {
+                    origin: String::from("--set"),
+                    message: format!("expected KEY=VALUE, got {:?}", entry)
+                })
+            },
+        }
+    }
+
+    return Ok(())
+}
+
+///Interpret a raw string as a TOML value, falling back to a plain string.
+fn parse_value(raw: &str) -> Value{
+    match format!("value = {}", raw).parse::<Table>(){
+        Ok(mut parsed) => parsed.remove("value").unwrap_or_else(|| Value::String(raw.to_string())),
+        Err(_) => Value::String(raw.to_string()),
+    }
+}
+
+///Set a nested key, creating intermediate tables.
+fn set_path(table: &mut Table, path: &[String], value: Value, origin: &str) -> Result<(), ConfigError>{
+    let invalid = || ConfigError::Parse {
+        origin: origin.to_string(),
+        message: format!("invalid key {:?}", path.join("."))
+    };
+
+    match path{
+        [] => Err(invalid()),
+        [key] if !key.is_empty() => {
+            table.insert(key.clone(), value);
+            Ok(())
+        },
+        [key, rest @ ..] if !key.is_empty() => {
+            let entry = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+
+            match entry{
+                Value::Table(inner) => set_path(inner, rest, value, origin),
+                _ => Err(invalid()),
+            }
+        },
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,81 @@
+use std::{env, fs, path::PathBuf};
+
+use super::{cli::{Cli, Command, MigrateAction}, server_config::{ConfigError, ServerConfig}};
+
+
+///Configuration file in the temp directory, removed when dropped.
+struct ConfigFile(PathBuf);
+
+impl ConfigFile{
+    fn new(name: &str, contents: &str) -> Self{
+        let path = env::temp_dir().join(format!("almc-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).expect("config file");
+        return ConfigFile(path)
+    }
+}
+
+impl Drop for ConfigFile{
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+///Cli loading only the database settings, so the rest need not validate.
+fn migrate_cli(file: &ConfigFile) -> Cli{
+    return Cli {
+        config: Some(file.0.clone()),
+        command: Some(Command::Migrate { action: MigrateAction::Status }),
+        ..Cli::default()
+    }
+}
+
+
+#[test]
+fn flags_override_environment_overrides_file(){
+    let file = ConfigFile::new("precedence", "[database]\npath = \"file.db3\"\npool_size = 2\nbusy_timeout_ms = 1000\nstatement_cache_size = 7\n");
+
+    //No other test reads these
+    env::set_var("ALMC_DATABASE__PATH", "env.db3");
+    env::set_var("ALMC_DATABASE__POOL_SIZE", "4");
+    env::set_var("ALMC_DATABASE__BUSY_TIMEOUT_MS", "2000");
+
+    let cli = Cli {
+        database: Some(String::from("cli.db3")),
+        overrides: vec![String::from("database.busy_timeout_ms = 3000")],
+        ..migrate_cli(&file)
+    };
+    let loaded = ServerConfig::load(&cli);
+
+    env::remove_var("ALMC_DATABASE__PATH");
+    env::remove_var("ALMC_DATABASE__POOL_SIZE");
+    env::remove_var("ALMC_DATABASE__BUSY_TIMEOUT_MS");
+
+    let config = loaded.expect("configuration");
+    assert_eq!(config.database.path, "cli.db3");
+    assert_eq!(config.database.busy_timeout_ms, 3000);
+    assert_eq!(config.database.pool_size, 4);
+    assert_eq!(config.database.statement_cache_size, 7);
+    //Untouched keys keep their defaults
+    assert_eq!(config.database.blocking_threads, 16);
+}
+
+#[test]
+fn malformed_overrides_are_refused(){
+    let file = ConfigFile::new("overrides", "");
+
+    let cli = Cli { overrides: vec![String::from("database.pool_size")], ..migrate_cli(&file) };
+    assert!(matches!(ServerConfig::load(&cli), Err(ConfigError::Parse { .. })));
+
+    //A key below a plain value
+    let cli = Cli { overrides: vec![String::from("database.path.inner=1")], ..migrate_cli(&file) };
+    assert!(matches!(ServerConfig::load(&cli), Err(ConfigError::Parse { .. })));
+
+    let cli = Cli { overrides: vec![String::from("database.pool_size=\"many\"")], ..migrate_cli(&file) };
+    assert!(matches!(ServerConfig::load(&cli), Err(ConfigError::Parse { .. })));
+}
+
+#[test]
+fn explicit_config_file_must_exist(){
+    let cli = Cli { config: Some(env::temp_dir().join("almc-missing.toml")), ..Cli::default() };
+    assert!(matches!(ServerConfig::load(&cli), Err(ConfigError::Io { .. })));
+}
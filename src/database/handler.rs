@@ -1,249 +1,735 @@
 use std::{io, str::FromStr};
 
 use argon2::password_hash::SaltString;
-use rusqlite::{Connection, Error, Result};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{types::Type, Error, Result, Row, Transaction, TransactionBehavior};
 use uuid::Uuid;
 
-use crate::models::database_models::{Session, User};
+use crate::{auth::lockout::ACCOUNT, models::database_models::{AppliedMigration, Guest, LoginAttempt, MaintenanceRun, OutboxMail, Session, User}};
 
-static DATABASE_PATH: &'static str  = "./user_database.db3";
+use super::{migrations::{Migration, MigrationError}, store::{GuestStore, LoginAttemptStore, MailStore, MaintenanceStore, RateLimitStore, SessionStore, TokenStore, UserStore}};
 
 
+///Queries on one pooled connection. Statements are prepared through the connection's statement cache.
 pub struct DatabaseHandler{
-    connection: Connection
+    connection: PooledConnection<SqliteConnectionManager>
 }
 impl DatabaseHandler{
-    ///Get new database handler instance.
-    pub fn new() -> Result<DatabaseHandler, Error>{
-        let connection = Connection::open(DATABASE_PATH);
-
-        if connection.is_ok() {
-            return Ok(DatabaseHandler {
-                connection: connection.unwrap(),
-            });
+    ///Get new database handler instance on a connection taken from the pool.
+    pub fn new(connection: PooledConnection<SqliteConnectionManager>) -> DatabaseHandler{
+        return DatabaseHandler {
+            connection: connection,
         }
-        return Err(connection.err().unwrap());
     }
+
     
-    ///Initialize database tables.
-    pub fn initialize_tables(&self) -> Result<usize, Error>{
-        let user = self.connection.execute(
-            "CREATE TABLE IF NOT EXISTS user(
-                id TEXT PRIMARY KEY,
-                username TEXT NOT NULL,
-                password TEXT NOT NULL,
-                active_sessions INTEGER DEFAULT 0,
-                salt TEXT NOT NULL
+    ///Schema version of the database, the version of the last migration applied.
+    pub fn schema_version(&self) -> Result<u32, Error>{
+        return self.connection.pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+
+    ///Create the table recording applied migrations.
+    fn initialize_migrations(&self) -> Result<usize, Error>{
+        return self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations(
+                version INTEGER PRIMARY KEY,
+                description TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
             );",
-        (),
-        );
+        ())
+    }
+
+    ///Migrations recorded as applied, oldest first. Read only, a database never migrated has none.
+    pub fn get_applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error>{
+        let tracked = self.connection.prepare_cached(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'"
+        )?.exists(())?;
 
-        if user.is_err(){
-            return user
+        if !tracked{
+            return Ok(vec![])
         }
 
-        let session = self.connection.execute(
-            "CREATE TABLE IF NOT EXISTS session(
-                session_id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL REFERENCES user(id)
-            );", 
-            (),
-        );
+        let mut statement = self.connection.prepare_cached(
+            "SELECT version, description, applied_at FROM schema_migrations ORDER BY version"
+        )?;
 
-        if session.is_err(){
-            return session
-        }
+        let migrations = statement.query_map((), |row| {
+            Ok(AppliedMigration::new(row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
 
-        let guest = self.connection.execute(
-            "CREATE TABLE IF NOT EXISTS guest(
-                id TEXT PRIMARY KEY,
-                session_id TEXT NOT NULL
-            );", 
-        ());
-        
-        if guest.is_err(){
-            return guest
+        return migrations.collect()
+    }
+
+    ///Apply one migration in its own transaction, recording it and raising the schema version.
+    ///A failing step leaves the database as it was. Returns false when another instance applied it first.
+    pub fn apply_migration(&self, migration: &Migration, now: i64) -> Result<bool, MigrationError>{
+        self.initialize_migrations()?;
+
+        //Immediate, so two instances starting together do not both run the step
+        let transaction = Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)?;
+        let version: u32 = transaction.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        if version >= migration.version{
+            return Ok(false)
         }
 
-        return Ok(user.unwrap() + session.unwrap() + guest.unwrap())
+        migration.apply(&transaction)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            (migration.version, migration.description, now)
+        )?;
+        transaction.pragma_update(None, "user_version", migration.version)?;
+        transaction.commit()?;
+
+        return Ok(true)
     }
 
     ///Query database for debugging.
+    #[allow(dead_code)]
     pub fn query_db(&self) {
         loop{
             let mut string_query = String::new();
             let _ = io::stdin().read_line(&mut string_query);
-            let statement = self.connection.prepare(&string_query);
-
-            match statement.unwrap().query(rusqlite::params![]){
-                Ok(mut result) => {
-                    loop{
-                        let row = result.next().unwrap();
-
-                        if row.is_some(){
-                            println!("Row {:?}", row);
-                        }
-                    }
+            let mut statement = match self.connection.prepare(&string_query){
+                Ok(statement) => statement,
+                Err(error) => {
+                    println!("Error {:?}", error);
+                    continue
                 },
+            };
+
+            let mut rows = match statement.query(rusqlite::params![]){
+                Ok(rows) => rows,
                 Err(error) => {
                     println!("Error {:?}", error);
+                    continue
                 },
+            };
+
+            while let Ok(Some(row)) = rows.next(){
+                println!("Row {:?}", row);
             }
         }
     }
+}
 
-    ///Get all users with matching username.
-    pub fn get_users(&self, username: &String) -> Result<Vec<User>, Error>{
-        let statement = self.connection.prepare("SELECT * FROM user WHERE username = ?1");
-
-        match statement.unwrap().query(rusqlite::params![username]){
-            Ok(mut rows) => {
-                let mut users: Vec<User> = vec![];
-                
-                loop{
-                    let row = rows.next().unwrap();
-                    
-                    match row{
-                        Some(user) => {
-                            let id: String = user.get_unwrap(0);
-                            let username: String = user.get_unwrap(1);
-                            let password: String = user.get_unwrap(2);
-                            let active_sessions: i32 = user.get_unwrap(3);
-                            let salt: String = user.get_unwrap(4);
-                            users.push(User::new(
-                                Uuid::from_str(&id).unwrap(), 
-                                username, 
-                                password, 
-                                active_sessions, 
-                                SaltString::from_b64(&salt).unwrap()
-                            ))
-                        },
-                        None => {
-                            return Ok(users);
-                        },
-                    }
-                }
-            },
-            Err(error) => {
-                return Err(error)
-            },
-        }
+impl UserStore for DatabaseHandler{
+    fn username_exists(&self, username: &str) -> Result<bool, Error>{
+        let mut statement = self.connection.prepare_cached("SELECT 1 FROM user WHERE username = ?1")?;
+
+        return statement.exists(rusqlite::params![username])
     }
 
-    ///Check if user/session id generated exists in database.
-    pub fn id_exists(&self, target: &String, id: &Uuid) -> Result<bool, Error>{
-        let query = format!("SELECT * FROM {} WHERE id = ?1", target);
-        let statement = self.connection.prepare(&query.as_str());
-
-        match statement.unwrap().query(rusqlite::params![id.to_string()]){
-            Ok(mut rows) => {
-
-                let mut found = false;
-
-                loop{
-                    let row = rows.next().unwrap();
-                    
-                    match row{
-                        Some(retrieved) => {
-                            let target_id: String = retrieved.get_unwrap(0);
-
-                            if target_id.eq(&id.to_string()){
-                                found = true;
-                            }
-
-                        },
-                        None => {
-                            return Ok(found);
-                        },
-                    }
-                }
-            },
-            Err(error) => {
-                return Err(error)
-            },
-        }
+    fn get_users(&self, username: &str) -> Result<Vec<User>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, username, password, active_sessions, salt, email, email_verified, display_username, rehash_required FROM user WHERE username = ?1"
+        )?;
 
+        let users = statement.query_map(rusqlite::params![username], user_from_row)?;
+        return users.collect()
     }
 
-    ///Insert new user to database.
-    pub fn insert_user(&self, user: User) -> Result<usize, Error>{
-        let statement = self.connection.prepare(
-            "INSERT INTO user(id, username, password, active_sessions, salt)
-            VALUES (?1, ?2, ?3, ?4, ?5)"
-        );
+    fn list_users(&self) -> Result<Vec<User>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, username, password, active_sessions, salt, email, email_verified, display_username, rehash_required FROM user ORDER BY id"
+        )?;
+
+        let users = statement.query_map((), user_from_row)?;
+        return users.collect()
+    }
+
+    fn migrate_username(&self, user_id: &Uuid, legacy: &str, index: &str, display_username: Option<&str>) -> Result<usize, Error>{
+        return self.connection.execute(
+            "UPDATE user SET username = ?1, display_username = ?2 WHERE id = ?3 AND username = ?4",
+            (index, display_username, user_id.to_string(), legacy)
+        )
+    }
+
+    fn id_exists(&self, target: &str, id: &Uuid) -> Result<bool, Error>{
+        let column = match target{
+            "session" => "session_id",
+            _ => "id",
+        };
+        let query = format!("SELECT 1 FROM {} WHERE {} = ?1", target, column);
+        let mut statement = self.connection.prepare_cached(query.as_str())?;
+
+        return statement.exists(rusqlite::params![id.to_string()])
+    }
+
+    fn insert_user(&self, user: User) -> Result<usize, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO user(id, username, password, active_sessions, salt, email, email_verified, display_username)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+        )?;
         
-        return statement.unwrap().execute((
+        return statement.execute((
             user.get_id().to_string(), 
             user.get_username(), 
             user.get_password(),
             user.get_active_sessions(), 
-            user.get_salt().to_string()
+            user.get_salt().to_string(),
+            user.get_email(),
+            user.is_email_verified(),
+            user.get_display_username()
         ))
     }
 
-    ///Get session with matching id.
-    pub fn get_session_from_id(&self, session_id: &Uuid) -> Result<Option<Session>, Error>{
-        let statement = self.connection.prepare(
-            "SELECT * FROM session WHERE session_id = ?1"
-        );
+    fn get_user_from_id(&self, user_id: &Uuid) -> Result<Option<User>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, username, password, active_sessions, salt, email, email_verified, display_username, rehash_required FROM user WHERE id = ?1"
+        )?;
+
+        let mut users = statement.query_map(rusqlite::params![user_id.to_string()], user_from_row)?;
+        return users.next().transpose()
+    }
+
+    fn rehash_password(&self, user_id: &Uuid, stored: &str, password: &str, salt: &SaltString) -> Result<usize, Error>{
+        return self.connection.execute(
+            "UPDATE user SET password = ?1, salt = ?2, rehash_required = 0 WHERE id = ?3 AND password = ?4",
+            (password, salt.to_string(), user_id.to_string(), stored)
+        )
+    }
+
+    fn get_unflagged_salts(&self) -> Result<Vec<(String, String)>, Error>{
+        let mut statement = self.connection.prepare_cached("SELECT id, salt FROM user WHERE rehash_required = 0")?;
 
-        match statement.unwrap().query(rusqlite::params![session_id.to_string()]){
-            Ok(mut rows) => {
-                let mut sessions: Vec<Session> = vec![];
-
-                loop{
-                    let row = rows.next().unwrap();
-                    
-                    match row{
-                        Some(session) => {
-                            let session_id: String = session.get_unwrap(0);
-                            let user_id: String = session.get_unwrap(1);
-                            sessions.push(Session::new(
-                                Uuid::from_str(&session_id).unwrap(), 
-                                Uuid::from_str(&user_id).unwrap(),
-                            ))
-                        },
-                        None => {
-                            println!("Sessions from db: {:?}", sessions);
-                            if sessions.len() == 0{
-                                return Ok(None)
-                            }
-
-                            return Ok(Some(sessions.pop().unwrap()));
-                        },
-                    }
-                }
-            },
-            Err(error) => {
-                return Err(error)
-            },
+        let salts = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        return salts.collect()
+    }
+
+    fn flag_rehash_required(&self, user_ids: &[String]) -> Result<usize, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+        let mut rows = 0;
+
+        {
+            let mut statement = transaction.prepare_cached("UPDATE user SET rehash_required = 1 WHERE id = ?1")?;
+
+            for user_id in user_ids{
+                rows += statement.execute(rusqlite::params![user_id])?;
+            }
         }
+
+        transaction.commit()?;
+        return Ok(rows)
+    }
+
+    fn change_password(&self, user_id: &Uuid, password: &str, salt: &SaltString, keep_session: Option<&Uuid>) -> Result<usize, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+
+        transaction.execute(
+            "UPDATE user SET password = ?2, salt = ?3, rehash_required = 0 WHERE id = ?1",
+            (user_id.to_string(), password, salt.to_string())
+        )?;
+
+        let sessions = match keep_session{
+            Some(session_id) => transaction.execute(
+                "DELETE FROM session WHERE user_id = ?1 AND session_id != ?2",
+                (user_id.to_string(), session_id.to_string())
+            )?,
+            None => 0,
+        };
+
+        transaction.commit()?;
+        return Ok(sessions)
     }
 
-    ///Insert new session to database.
-    pub fn insert_session(&self, session: &Session) -> Result<usize, Error>{
-        let statement = self.connection.prepare(
-            "INSERT INTO session(session_id, user_id) 
-            VALUES (?1, ?2)"
+    fn get_user_from_email(&self, email: &str) -> Result<Option<User>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, username, password, active_sessions, salt, email, email_verified, display_username, rehash_required FROM user WHERE email = ?1"
+        )?;
+
+        let mut users = statement.query_map(rusqlite::params![email], user_from_row)?;
+        return users.next().transpose()
+    }
+
+    fn email_exists(&self, email: &str) -> Result<bool, Error>{
+        let mut statement = self.connection.prepare_cached("SELECT 1 FROM user WHERE email = ?1")?;
+
+        return statement.exists(rusqlite::params![email])
+    }
+}
+
+impl TokenStore for DatabaseHandler{
+    fn insert_verification_token(&self, token_hash: &str, user_id: &Uuid, purpose: &str, created_at: i64, expires_at: i64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "INSERT INTO verification_token(token_hash, user_id, purpose, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)"
         );
 
-        return statement.unwrap().execute((
+        return statement?.execute((
+            token_hash,
+            user_id.to_string(),
+            purpose,
+            created_at,
+            expires_at
+        ))
+    }
+
+    fn delete_verification_tokens(&self, user_id: &Uuid, purpose: &str) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "DELETE FROM verification_token WHERE user_id = ?1 AND purpose = ?2"
+        );
+
+        return statement?.execute((user_id.to_string(), purpose))
+    }
+
+    fn find_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT user_id FROM verification_token WHERE token_hash = ?1 AND purpose = ?2 AND expires_at > ?3"
+        )?;
+
+        let mut rows = statement.query_map(rusqlite::params![token_hash, purpose, now], |row| uuid_column(row, 0))?;
+        return rows.next().transpose()
+    }
+
+    fn consume_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+        let user_id = take_verification_token(&transaction, token_hash, purpose, now)?;
+
+        transaction.commit()?;
+        return Ok(user_id)
+    }
+
+    fn reset_password(&self, token_hash: &str, purpose: &str, now: i64, user_id: &Uuid, password: &str, salt: &SaltString) -> Result<Option<usize>, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+
+        match take_verification_token(&transaction, token_hash, purpose, now)?{
+            Some(token_user) if token_user.eq(user_id) => {},
+            _ => return Ok(None),
+        }
+
+        transaction.execute(
+            "UPDATE user SET password = ?2, salt = ?3, email_verified = 1, rehash_required = 0 WHERE id = ?1",
+            (user_id.to_string(), password, salt.to_string())
+        )?;
+        let sessions = transaction.execute(
+            "DELETE FROM session WHERE user_id = ?1",
+            rusqlite::params![user_id.to_string()]
+        )?;
+        //Failed logins are counted under the stored username, see `find_account`
+        transaction.execute(
+            "DELETE FROM login_attempts WHERE scope = ?1 AND key = (SELECT username FROM user WHERE id = ?2)",
+            (ACCOUNT, user_id.to_string())
+        )?;
+
+        transaction.commit()?;
+        return Ok(Some(sessions))
+    }
+
+    fn confirm_email(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+        let user_id = take_verification_token(&transaction, token_hash, purpose, now)?;
+
+        if let Some(user_id) = &user_id{
+            transaction.execute(
+                "UPDATE user SET email_verified = 1 WHERE id = ?1",
+                rusqlite::params![user_id.to_string()]
+            )?;
+        }
+
+        transaction.commit()?;
+        return Ok(user_id)
+    }
+}
+
+impl SessionStore for DatabaseHandler{
+    fn get_session_from_id(&self, session_id: &Uuid) -> Result<Option<Session>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT session_id, user_id, created_at, last_seen_at, expires_at FROM session WHERE session_id = ?1"
+        )?;
+
+        let mut sessions = statement.query_map(rusqlite::params![session_id.to_string()], session_from_row)?;
+        return sessions.next().transpose()
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<usize, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO session(session_id, user_id, created_at, last_seen_at, expires_at) 
+            VALUES (?1, ?2, ?3, ?4, ?5)"
+        )?;
+
+        return statement.execute((
             session.get_id().to_string(),
-            session.get_user_id().to_string()
+            session.get_user_id().to_string(),
+            session.get_created_at(),
+            session.get_last_seen_at(),
+            session.get_expires_at()
         ))
     }
 
-    ///Insert new guest user to database.
-    pub fn insert_guest(&self, session_id: &Uuid, guest_id: &Uuid) -> Result<usize, Error>{
-         let statement = self.connection.prepare(
-            "INSERT INTO guest(id, session_id)
-            VALUES (?1, ?2)"
+    fn touch_session(&self, session_id: &Uuid, last_seen_at: i64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "UPDATE session SET last_seen_at = ?2 WHERE session_id = ?1"
         );
-        
-        return statement.unwrap().execute((
+
+        return statement?.execute((
             session_id.to_string(),
-            guest_id.to_string()
+            last_seen_at
         ))
+    }
+
+    fn delete_session(&self, session_id: &Uuid, user_id: &Uuid) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "DELETE FROM session WHERE session_id = ?1 AND user_id = ?2"
+        );
+
+        return statement?.execute((
+            session_id.to_string(),
+            user_id.to_string()
+        ))
+    }
+
+    fn delete_user_sessions(&self, user_id: &Uuid) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "DELETE FROM session WHERE user_id = ?1"
+        );
+
+        return statement?.execute(rusqlite::params![user_id.to_string()])
+    }
+}
+
+impl GuestStore for DatabaseHandler{
+    fn insert_guest(&self, guest_session: &Session) -> Result<usize, Error>{
+         let statement = self.connection.prepare_cached(
+            "INSERT INTO guest(id, session_id, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4)"
+        );
+        
+        return statement?.execute((
+            guest_session.get_user_id().to_string(),
+            guest_session.get_id().to_string(),
+            guest_session.get_created_at(),
+            guest_session.get_expires_at()
+        ))
+
+    }
+
+    fn renew_guest(&self, old_session_id: &Uuid, guest_session: &Session) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "UPDATE guest SET session_id = ?1, expires_at = ?2 WHERE id = ?3 AND session_id = ?4"
+        );
+
+        return statement?.execute((
+            guest_session.get_id().to_string(),
+            guest_session.get_expires_at(),
+            guest_session.get_user_id().to_string(),
+            old_session_id.to_string()
+        ))
+    }
+
+    fn get_guest(&self, guest_id: &Uuid) -> Result<Option<Guest>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, session_id, created_at, expires_at FROM guest WHERE id = ?1"
+        )?;
+
+        let mut guests = statement.query_map(rusqlite::params![guest_id.to_string()], |row| {
+            Ok(Guest::new(
+                uuid_column(row, 0)?,
+                uuid_column(row, 1)?,
+                row.get(2)?,
+                row.get(3)?
+            ))
+        })?;
+
+        return guests.next().transpose()
+    }
+
+    fn upgrade_guest(&self, user: User, session: &Session) -> Result<usize, Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+
+        let mut rows = transaction.execute(
+            "INSERT INTO user(id, username, password, active_sessions, salt, email, email_verified, display_username)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            (
+                user.get_id().to_string(),
+                user.get_username(),
+                user.get_password(),
+                user.get_active_sessions(),
+                user.get_salt().to_string(),
+                user.get_email(),
+                user.is_email_verified(),
+                user.get_display_username()
+            )
+        )?;
+        rows += transaction.execute(
+            "INSERT INTO session(session_id, user_id, created_at, last_seen_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                session.get_id().to_string(),
+                session.get_user_id().to_string(),
+                session.get_created_at(),
+                session.get_last_seen_at(),
+                session.get_expires_at()
+            )
+        )?;
+        rows += transaction.execute(
+            "DELETE FROM guest WHERE id = ?1",
+            rusqlite::params![user.get_id().to_string()]
+        )?;
+
+        transaction.commit()?;
+        return Ok(rows)
+    }
+
+    fn delete_guest(&self, guest_id: &Uuid, session_id: &Uuid) -> Result<usize, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "DELETE FROM guest WHERE id = ?1 AND session_id = ?2"
+        )?;
+
+        return statement.execute((guest_id.to_string(), session_id.to_string()))
+    }
+
+    fn delete_expired_guests(&self, now: i64) -> Result<(usize, usize), Error>{
+        let transaction = self.connection.unchecked_transaction()?;
+
+        let sessions = transaction.execute(
+            "DELETE FROM session WHERE user_id IN (SELECT id FROM guest WHERE expires_at <= ?1)",
+            rusqlite::params![now]
+        )?;
+        let guests = transaction.execute(
+            "DELETE FROM guest WHERE expires_at <= ?1",
+            rusqlite::params![now]
+        )?;
+
+        transaction.commit()?;
+        return Ok((guests, sessions))
+    }
+}
+
+impl LoginAttemptStore for DatabaseHandler{
+    fn get_login_attempt(&self, scope: &str, key: &str) -> Result<Option<LoginAttempt>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT scope, key, failures, last_failure_at, locked_until FROM login_attempts WHERE scope = ?1 AND key = ?2"
+        )?;
+
+        let mut attempts = statement.query_map(rusqlite::params![scope, key], login_attempt_from_row)?;
+        return attempts.next().transpose()
+    }
+
+    fn record_login_failure(&self, scope: &str, key: &str, now: i64, window_seconds: i64) -> Result<LoginAttempt, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO login_attempts(scope, key, failures, last_failure_at, locked_until)
+            VALUES (?1, ?2, 1, ?3, 0)
+            ON CONFLICT(scope, key) DO UPDATE SET
+                failures = CASE WHEN last_failure_at <= ?3 - ?4 THEN 1 ELSE failures + 1 END,
+                last_failure_at = ?3
+            RETURNING scope, key, failures, last_failure_at, locked_until"
+        )?;
+
+        return statement.query_row(rusqlite::params![scope, key, now, window_seconds], login_attempt_from_row)
+    }
+
+    fn lock_login(&self, scope: &str, key: &str, locked_until: i64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "UPDATE login_attempts SET failures = 0, locked_until = ?3 WHERE scope = ?1 AND key = ?2"
+        );
+
+        return statement?.execute((scope, key, locked_until))
+    }
+
+    fn clear_login_attempts(&self, scope: &str, key: &str) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "DELETE FROM login_attempts WHERE scope = ?1 AND key = ?2"
+        );
+
+        return statement?.execute((scope, key))
+    }
+
+    fn get_login_locks(&self, now: i64) -> Result<Vec<LoginAttempt>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT scope, key, failures, last_failure_at, locked_until FROM login_attempts
+            WHERE locked_until > ?1 ORDER BY locked_until DESC"
+        )?;
+
+        let attempts = statement.query_map(rusqlite::params![now], login_attempt_from_row)?;
+        return attempts.collect()
+    }
+}
+
+impl MailStore for DatabaseHandler{
+    fn enqueue_mail(&self, recipient: &str, subject: &str, body: &str, now: i64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "INSERT INTO mail_outbox(recipient, subject, body, created_at, next_attempt_at)
+            VALUES (?1, ?2, ?3, ?4, ?4)"
+        );
 
+        return statement?.execute((recipient, subject, body, now))
     }
+
+    fn claim_due_mail(&self, now: i64, lease_until: i64, limit: usize) -> Result<Vec<OutboxMail>, Error>{
+        //One statement, so two runs can never claim the same message
+        let mut statement = self.connection.prepare_cached(
+            "UPDATE mail_outbox SET status = 'sending', next_attempt_at = ?2
+            WHERE id IN (
+                SELECT id FROM mail_outbox
+                WHERE status IN ('pending', 'sending') AND next_attempt_at <= ?1 ORDER BY id LIMIT ?3
+            )
+            RETURNING id, recipient, subject, body, attempts"
+        )?;
+
+        let mails = statement.query_map(rusqlite::params![now, lease_until, limit as i64], |row| {
+            Ok(OutboxMail::new(
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?
+            ))
+        })?;
+
+        let mut mails = mails.collect::<Result<Vec<OutboxMail>, Error>>()?;
+        mails.sort_by_key(|mail| *mail.get_id());
+        return Ok(mails)
+    }
+
+    fn mark_mail_sent(&self, id: i64, now: i64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "UPDATE mail_outbox SET status = 'sent', attempts = attempts + 1, sent_at = ?2, last_error = NULL, body = '' WHERE id = ?1"
+        );
+
+        return statement?.execute((id, now))
+    }
+
+    fn mark_mail_failed(&self, id: i64, error: &str, next_attempt_at: Option<i64>) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "UPDATE mail_outbox SET attempts = attempts + 1, last_error = ?2,
+                status = CASE WHEN ?3 IS NULL THEN 'failed' ELSE 'pending' END,
+                next_attempt_at = COALESCE(?3, next_attempt_at),
+                body = CASE WHEN ?3 IS NULL THEN '' ELSE body END
+            WHERE id = ?1"
+        );
+
+        return statement?.execute((id, error, next_attempt_at))
+    }
+}
+
+impl MaintenanceStore for DatabaseHandler{
+    fn insert_maintenance_run(&self, run: &MaintenanceRun) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "INSERT INTO maintenance_runs(job, trigger, started_at, duration_ms, success, rows_affected, attempts, error)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+        );
+
+        return statement?.execute((
+            run.get_job(),
+            run.get_trigger(),
+            run.get_started_at(),
+            run.get_duration_ms(),
+            run.get_success(),
+            run.get_rows_affected(),
+            run.get_attempts(),
+            run.get_error()
+        ))
+    }
+
+    fn get_maintenance_runs(&self, job: &str, limit: usize) -> Result<Vec<MaintenanceRun>, Error>{
+        let mut statement = self.connection.prepare_cached(
+            "SELECT job, trigger, started_at, duration_ms, success, rows_affected, attempts, error
+            FROM maintenance_runs WHERE job = ?1 ORDER BY id DESC LIMIT ?2"
+        )?;
+
+        let runs = statement.query_map(rusqlite::params![job, limit as i64], |row| {
+            Ok(MaintenanceRun::new(
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?
+            ))
+        })?;
+
+        return runs.collect()
+    }
+}
+
+impl RateLimitStore for DatabaseHandler{
+    fn take_rate_limit_token(&self, key: &str, capacity: f64, refill_per_second: f64, now: f64) -> Result<(bool, f64), Error>{
+        //One statement, so instances sharing the database agree
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO rate_limit_buckets(key, tokens, updated_at, allowed)
+            VALUES (?1, ?2 - 1, ?4, 1)
+            ON CONFLICT(key) DO UPDATE SET
+                allowed = MIN(?2, tokens + (?4 - updated_at) * ?3) >= 1,
+                tokens = MIN(?2, tokens + (?4 - updated_at) * ?3) - (MIN(?2, tokens + (?4 - updated_at) * ?3) >= 1),
+                updated_at = ?4
+            RETURNING allowed, tokens"
+        )?;
+
+        return statement.query_row(rusqlite::params![key, capacity, refill_per_second, now], |row| Ok((row.get(0)?, row.get(1)?)))
+    }
+
+    fn delete_idle_rate_limit_buckets(&self, before: f64) -> Result<usize, Error>{
+        let statement = self.connection.prepare_cached(
+            "DELETE FROM rate_limit_buckets WHERE updated_at < ?1"
+        );
+
+        return statement?.execute(rusqlite::params![before])
+    }
+}
+
+///Map a row of `id, username, password, active_sessions, salt, email, email_verified, display_username, rehash_required` to a user.
+fn user_from_row(row: &Row) -> Result<User, Error>{
+    let id = uuid_column(row, 0)?;
+    let salt: String = row.get(4)?;
+    let salt = SaltString::from_b64(&salt)
+        .map_err(|error| Error::FromSqlConversionFailure(4, Type::Text, error.to_string().into()))?;
+
+    return Ok(User::new(
+        id,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        salt,
+        row.get(5)?,
+        row.get(6)?,
+        row.get(7)?,
+        row.get(8)?
+    ))
+}
+
+fn session_from_row(row: &Row) -> Result<Session, Error>{
+    return Ok(Session::new(
+        uuid_column(row, 0)?,
+        uuid_column(row, 1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?
+    ))
+}
+
+///Uuid stored as text. A malformed value is a conversion error, like any other bad column.
+fn uuid_column(row: &Row, index: usize) -> Result<Uuid, Error>{
+    let value: String = row.get(index)?;
+
+    return Uuid::from_str(&value)
+        .map_err(|error| Error::FromSqlConversionFailure(index, Type::Text, Box::new(error)))
+}
+
+fn login_attempt_from_row(row: &Row) -> Result<LoginAttempt, Error>{
+    return Ok(LoginAttempt::new(
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?
+    ))
+}
+
+///Delete a verification token and return the user it was issued to.
+///Expired tokens are removed on the way, so they never match.
+fn take_verification_token(transaction: &Transaction, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+    transaction.execute(
+        "DELETE FROM verification_token WHERE expires_at <= ?1",
+        rusqlite::params![now]
+    )?;
+
+    let mut statement = transaction.prepare_cached(
+        "DELETE FROM verification_token WHERE token_hash = ?1 AND purpose = ?2 RETURNING user_id"
+    )?;
+    let mut rows = statement.query_map(rusqlite::params![token_hash, purpose], |row| uuid_column(row, 0))?;
+    return rows.next().transpose()
 }
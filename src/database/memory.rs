@@ -0,0 +1,647 @@
+use std::{collections::{BTreeMap, HashMap}, future::{ready, Future}, sync::{Arc, Mutex, MutexGuard}};
+
+use argon2::password_hash::SaltString;
+use rusqlite::{ffi, Error};
+use uuid::Uuid;
+
+use crate::{auth::lockout::ACCOUNT, models::database_models::{Guest, LoginAttempt, MaintenanceRun, OutboxMail, Session, User}, rate_limit::store::TokenBucket};
+
+use super::store::{Backend, DatabaseError, GuestStore, LoginAttemptStore, MailStore, MaintenanceStore, RateLimitStore, SessionStore, TokenStore, UserStore};
+
+
+///Backend keeping every table in memory, for tests and ephemeral demos. Nothing survives a restart.
+#[derive(Clone, Default)]
+pub struct MemoryDatabase{
+    handler: Arc<MemoryHandler>
+}
+
+impl Backend for MemoryDatabase{
+    type Store = MemoryHandler;
+
+    fn with<T>(&self, task: impl FnOnce(&MemoryHandler) -> Result<T, DatabaseError>) -> Result<T, DatabaseError>{
+        return task(&self.handler)
+    }
+
+    //Nothing to wait for, so work runs right away instead of on the blocking pool
+    fn run<T, F>(&self, task: F) -> impl Future<Output = Result<T, DatabaseError>> + Send
+    where
+        F: FnOnce(&MemoryHandler) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        return ready(self.with(task))
+    }
+}
+
+
+///Rows of every table behind one lock. Each operation holds the lock throughout, so it applies as a whole,
+///and reports constraint violations with the errors SQLite gives.
+#[derive(Default)]
+pub struct MemoryHandler{
+    tables: Mutex<Tables>
+}
+
+#[derive(Default)]
+struct Tables{
+    users: BTreeMap<Uuid, UserRow>,
+    sessions: HashMap<Uuid, SessionRow>,
+    guests: HashMap<Uuid, GuestRow>,
+    tokens: HashMap<String, TokenRow>,
+    login_attempts: HashMap<(String, String), AttemptRow>,
+    maintenance_runs: Vec<MaintenanceRun>,
+    outbox: BTreeMap<i64, MailRow>,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+struct UserRow{
+    username: String,
+    password: String,
+    active_sessions: i32,
+    salt: SaltString,
+    email: Option<String>,
+    email_verified: bool,
+    display_username: Option<String>,
+    rehash_required: bool,
+}
+
+struct SessionRow{
+    user_id: Uuid,
+    created_at: i64,
+    last_seen_at: i64,
+    expires_at: i64,
+}
+
+struct GuestRow{
+    session_id: Uuid,
+    created_at: i64,
+    expires_at: i64,
+}
+
+struct TokenRow{
+    user_id: Uuid,
+    purpose: String,
+    expires_at: i64,
+}
+
+struct AttemptRow{
+    failures: i64,
+    last_failure_at: i64,
+    locked_until: i64,
+}
+
+#[derive(PartialEq)]
+enum MailStatus{
+    Pending,
+    Sending,
+    Sent,
+    Failed,
+}
+
+struct MailRow{
+    recipient: String,
+    subject: String,
+    body: String,
+    status: MailStatus,
+    attempts: i64,
+    next_attempt_at: i64,
+}
+
+impl MemoryHandler{
+    fn tables(&self) -> MutexGuard<'_, Tables>{
+        //A panic elsewhere leaves the rows readable, the same as a crashed connection leaves the file
+        return self.tables.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Tables{
+    ///Check the keys and unique columns of a new user, in the order SQLite reports them.
+    fn check_new_user(&self, user: &User) -> Result<(), Error>{
+        if self.users.contains_key(user.get_id()){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_PRIMARYKEY, "user.id"))
+        }
+
+        if self.users.values().any(|row| row.username.eq(user.get_username())){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_UNIQUE, "user.username"))
+        }
+
+        if user.get_email().is_some() && self.users.values().any(|row| row.email.eq(user.get_email())){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_UNIQUE, "user.email"))
+        }
+
+        return Ok(())
+    }
+
+    fn delete_sessions_where(&mut self, delete: impl Fn(&Uuid, &SessionRow) -> bool) -> usize{
+        let before = self.sessions.len();
+        self.sessions.retain(|session_id, session| !delete(session_id, session));
+
+        return before - self.sessions.len()
+    }
+
+    ///Delete a verification token and return the user it was issued to.
+    ///Expired tokens are removed on the way, so they never match.
+    fn take_verification_token(&mut self, token_hash: &str, purpose: &str, now: i64) -> Option<Uuid>{
+        self.tokens.retain(|_, token| token.expires_at > now);
+
+        match self.tokens.get(token_hash){
+            Some(token) if token.purpose == purpose => return self.tokens.remove(token_hash).map(|token| token.user_id),
+            _ => return None,
+        }
+    }
+}
+
+impl UserRow{
+    fn from_user(user: User) -> UserRow{
+        return UserRow {
+            salt: user.get_salt(),
+            username: user.get_username().clone(),
+            password: user.get_password().clone(),
+            active_sessions: *user.get_active_sessions(),
+            email: user.get_email().clone(),
+            email_verified: user.is_email_verified(),
+            display_username: user.get_display_username().clone(),
+            rehash_required: false
+        }
+    }
+
+    fn to_user(&self, id: &Uuid) -> User{
+        return User::new(
+            *id,
+            self.username.clone(),
+            self.password.clone(),
+            self.active_sessions,
+            self.salt.clone(),
+            self.email.clone(),
+            self.email_verified,
+            self.display_username.clone(),
+            self.rehash_required
+        )
+    }
+}
+
+impl SessionRow{
+    fn from_session(session: &Session) -> SessionRow{
+        return SessionRow {
+            user_id: *session.get_user_id(),
+            created_at: *session.get_created_at(),
+            last_seen_at: *session.get_last_seen_at(),
+            expires_at: *session.get_expires_at()
+        }
+    }
+}
+
+impl AttemptRow{
+    fn to_attempt(&self, scope: &str, key: &str) -> LoginAttempt{
+        return LoginAttempt::new(scope.to_string(), key.to_string(), self.failures, self.last_failure_at, self.locked_until)
+    }
+}
+
+
+impl UserStore for MemoryHandler{
+    fn username_exists(&self, username: &str) -> Result<bool, Error>{
+        return Ok(self.tables().users.values().any(|user| user.username == username))
+    }
+
+    fn get_users(&self, username: &str) -> Result<Vec<User>, Error>{
+        let tables = self.tables();
+
+        return Ok(tables.users.iter()
+            .filter(|(_, user)| user.username.eq(username))
+            .map(|(id, user)| user.to_user(id))
+            .collect())
+    }
+
+    fn list_users(&self) -> Result<Vec<User>, Error>{
+        return Ok(self.tables().users.iter().map(|(id, user)| user.to_user(id)).collect())
+    }
+
+    fn migrate_username(&self, user_id: &Uuid, legacy: &str, index: &str, display_username: Option<&str>) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        if tables.users.iter().any(|(id, user)| user.username == index && id.ne(user_id)){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_UNIQUE, "user.username"))
+        }
+
+        match tables.users.get_mut(user_id){
+            Some(user) if user.username == legacy => {
+                user.username = index.to_string();
+                user.display_username = display_username.map(|display| display.to_string());
+                return Ok(1)
+            },
+            _ => return Ok(0),
+        }
+    }
+
+    fn id_exists(&self, target: &str, id: &Uuid) -> Result<bool, Error>{
+        let tables = self.tables();
+
+        match target{
+            "user" => return Ok(tables.users.contains_key(id)),
+            "session" => return Ok(tables.sessions.contains_key(id)),
+            "guest" => return Ok(tables.guests.contains_key(id)),
+            _ => return Err(Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_ERROR), Some(format!("no such table: {}", target)))),
+        }
+    }
+
+    fn insert_user(&self, user: User) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        tables.check_new_user(&user)?;
+
+        tables.users.insert(*user.get_id(), UserRow::from_user(user));
+        return Ok(1)
+    }
+
+    fn get_user_from_id(&self, user_id: &Uuid) -> Result<Option<User>, Error>{
+        return Ok(self.tables().users.get(user_id).map(|user| user.to_user(user_id)))
+    }
+
+    fn rehash_password(&self, user_id: &Uuid, stored: &str, password: &str, salt: &SaltString) -> Result<usize, Error>{
+        match self.tables().users.get_mut(user_id){
+            Some(user) if user.password == stored => {
+                user.password = password.to_string();
+                user.salt = salt.clone();
+                user.rehash_required = false;
+                return Ok(1)
+            },
+            _ => return Ok(0),
+        }
+    }
+
+    fn get_unflagged_salts(&self) -> Result<Vec<(String, String)>, Error>{
+        return Ok(self.tables().users.iter()
+            .filter(|(_, user)| !user.rehash_required)
+            .map(|(id, user)| (id.to_string(), user.salt.to_string()))
+            .collect())
+    }
+
+    fn flag_rehash_required(&self, user_ids: &[String]) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        let mut rows = 0;
+
+        for user_id in user_ids{
+            if let Some(user) = user_id.parse::<Uuid>().ok().and_then(|user_id| tables.users.get_mut(&user_id)){
+                user.rehash_required = true;
+                rows += 1;
+            }
+        }
+
+        return Ok(rows)
+    }
+
+    fn change_password(&self, user_id: &Uuid, password: &str, salt: &SaltString, keep_session: Option<&Uuid>) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        if let Some(user) = tables.users.get_mut(user_id){
+            user.password = password.to_string();
+            user.salt = salt.clone();
+            user.rehash_required = false;
+        }
+
+        match keep_session{
+            Some(keep) => return Ok(tables.delete_sessions_where(|session_id, session| session.user_id.eq(user_id) && session_id.ne(keep))),
+            None => return Ok(0),
+        }
+    }
+
+    fn get_user_from_email(&self, email: &str) -> Result<Option<User>, Error>{
+        let tables = self.tables();
+
+        return Ok(tables.users.iter()
+            .find(|(_, user)| user.email.as_deref() == Some(email))
+            .map(|(id, user)| user.to_user(id)))
+    }
+
+    fn email_exists(&self, email: &str) -> Result<bool, Error>{
+        return Ok(self.tables().users.values().any(|user| user.email.as_deref() == Some(email)))
+    }
+}
+
+impl TokenStore for MemoryHandler{
+    fn insert_verification_token(&self, token_hash: &str, user_id: &Uuid, purpose: &str, _created_at: i64, expires_at: i64) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        if tables.tokens.contains_key(token_hash){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_PRIMARYKEY, "verification_token.token_hash"))
+        }
+
+        tables.tokens.insert(token_hash.to_string(), TokenRow {
+            user_id: *user_id,
+            purpose: purpose.to_string(),
+            expires_at: expires_at
+        });
+        return Ok(1)
+    }
+
+    fn delete_verification_tokens(&self, user_id: &Uuid, purpose: &str) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        let before = tables.tokens.len();
+
+        tables.tokens.retain(|_, token| !(token.user_id.eq(user_id) && token.purpose == purpose));
+        return Ok(before - tables.tokens.len())
+    }
+
+    fn find_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        match self.tables().tokens.get(token_hash){
+            Some(token) if token.purpose == purpose && token.expires_at > now => return Ok(Some(token.user_id)),
+            _ => return Ok(None),
+        }
+    }
+
+    fn consume_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        return Ok(self.tables().take_verification_token(token_hash, purpose, now))
+    }
+
+    fn reset_password(&self, token_hash: &str, purpose: &str, now: i64, user_id: &Uuid, password: &str, salt: &SaltString) -> Result<Option<usize>, Error>{
+        let mut tables = self.tables();
+
+        //A token of another user leaves everything untouched, as the rolled back transaction does
+        match tables.tokens.get(token_hash){
+            Some(token) if token.purpose == purpose && token.expires_at > now && token.user_id.eq(user_id) => {},
+            _ => return Ok(None),
+        }
+
+        tables.take_verification_token(token_hash, purpose, now);
+
+        if let Some(user) = tables.users.get_mut(user_id){
+            user.password = password.to_string();
+            user.salt = salt.clone();
+            user.email_verified = true;
+            user.rehash_required = false;
+
+            let account = (ACCOUNT.to_string(), user.username.clone());
+            tables.login_attempts.remove(&account);
+        }
+
+        return Ok(Some(tables.delete_sessions_where(|_, session| session.user_id.eq(user_id))))
+    }
+
+    fn confirm_email(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>{
+        let mut tables = self.tables();
+        let user_id = tables.take_verification_token(token_hash, purpose, now);
+
+        if let Some(user) = user_id.and_then(|user_id| tables.users.get_mut(&user_id)){
+            user.email_verified = true;
+        }
+
+        return Ok(user_id)
+    }
+}
+
+impl SessionStore for MemoryHandler{
+    fn get_session_from_id(&self, session_id: &Uuid) -> Result<Option<Session>, Error>{
+        return Ok(self.tables().sessions.get(session_id).map(|session| {
+            Session::new(*session_id, session.user_id, session.created_at, session.last_seen_at, session.expires_at)
+        }))
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        if tables.sessions.contains_key(session.get_id()){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_PRIMARYKEY, "session.session_id"))
+        }
+
+        tables.sessions.insert(*session.get_id(), SessionRow::from_session(session));
+        return Ok(1)
+    }
+
+    fn touch_session(&self, session_id: &Uuid, last_seen_at: i64) -> Result<usize, Error>{
+        match self.tables().sessions.get_mut(session_id){
+            Some(session) => {
+                session.last_seen_at = last_seen_at;
+                return Ok(1)
+            },
+            None => return Ok(0),
+        }
+    }
+
+    fn delete_session(&self, session_id: &Uuid, user_id: &Uuid) -> Result<usize, Error>{
+        return Ok(self.tables().delete_sessions_where(|id, session| id.eq(session_id) && session.user_id.eq(user_id)))
+    }
+
+    fn delete_user_sessions(&self, user_id: &Uuid) -> Result<usize, Error>{
+        return Ok(self.tables().delete_sessions_where(|_, session| session.user_id.eq(user_id)))
+    }
+}
+
+impl GuestStore for MemoryHandler{
+    fn insert_guest(&self, guest_session: &Session) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        if tables.guests.contains_key(guest_session.get_user_id()){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_PRIMARYKEY, "guest.id"))
+        }
+
+        tables.guests.insert(*guest_session.get_user_id(), GuestRow {
+            session_id: *guest_session.get_id(),
+            created_at: *guest_session.get_created_at(),
+            expires_at: *guest_session.get_expires_at()
+        });
+        return Ok(1)
+    }
+
+    fn renew_guest(&self, old_session_id: &Uuid, guest_session: &Session) -> Result<usize, Error>{
+        match self.tables().guests.get_mut(guest_session.get_user_id()){
+            Some(guest) if guest.session_id.eq(old_session_id) => {
+                guest.session_id = *guest_session.get_id();
+                guest.expires_at = *guest_session.get_expires_at();
+                return Ok(1)
+            },
+            _ => return Ok(0),
+        }
+    }
+
+    fn get_guest(&self, guest_id: &Uuid) -> Result<Option<Guest>, Error>{
+        return Ok(self.tables().guests.get(guest_id).map(|guest| {
+            Guest::new(*guest_id, guest.session_id, guest.created_at, guest.expires_at)
+        }))
+    }
+
+    fn upgrade_guest(&self, user: User, session: &Session) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        tables.check_new_user(&user)?;
+
+        if tables.sessions.contains_key(session.get_id()){
+            return Err(constraint(ffi::SQLITE_CONSTRAINT_PRIMARYKEY, "session.session_id"))
+        }
+
+        let user_id = *user.get_id();
+        tables.users.insert(user_id, UserRow::from_user(user));
+        tables.sessions.insert(*session.get_id(), SessionRow::from_session(session));
+
+        return Ok(2 + tables.guests.remove(&user_id).map_or(0, |_| 1))
+    }
+
+    fn delete_guest(&self, guest_id: &Uuid, session_id: &Uuid) -> Result<usize, Error>{
+        let mut tables = self.tables();
+
+        match tables.guests.get(guest_id){
+            Some(guest) if guest.session_id.eq(session_id) => {
+                tables.guests.remove(guest_id);
+                return Ok(1)
+            },
+            _ => return Ok(0),
+        }
+    }
+
+    fn delete_expired_guests(&self, now: i64) -> Result<(usize, usize), Error>{
+        let mut tables = self.tables();
+        let expired: Vec<Uuid> = tables.guests.iter()
+            .filter(|(_, guest)| guest.expires_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let sessions = tables.delete_sessions_where(|_, session| expired.contains(&session.user_id));
+        tables.guests.retain(|id, _| !expired.contains(id));
+
+        return Ok((expired.len(), sessions))
+    }
+}
+
+impl LoginAttemptStore for MemoryHandler{
+    fn get_login_attempt(&self, scope: &str, key: &str) -> Result<Option<LoginAttempt>, Error>{
+        let tables = self.tables();
+
+        return Ok(tables.login_attempts.get(&(scope.to_string(), key.to_string())).map(|attempt| attempt.to_attempt(scope, key)))
+    }
+
+    fn record_login_failure(&self, scope: &str, key: &str, now: i64, window_seconds: i64) -> Result<LoginAttempt, Error>{
+        let mut tables = self.tables();
+        let attempt = tables.login_attempts.entry((scope.to_string(), key.to_string()))
+            .and_modify(|attempt| {
+                attempt.failures = match attempt.last_failure_at <= now - window_seconds{
+                    true => 1,
+                    false => attempt.failures + 1,
+                };
+                attempt.last_failure_at = now;
+            })
+            .or_insert(AttemptRow { failures: 1, last_failure_at: now, locked_until: 0 });
+
+        return Ok(attempt.to_attempt(scope, key))
+    }
+
+    fn lock_login(&self, scope: &str, key: &str, locked_until: i64) -> Result<usize, Error>{
+        match self.tables().login_attempts.get_mut(&(scope.to_string(), key.to_string())){
+            Some(attempt) => {
+                attempt.failures = 0;
+                attempt.locked_until = locked_until;
+                return Ok(1)
+            },
+            None => return Ok(0),
+        }
+    }
+
+    fn clear_login_attempts(&self, scope: &str, key: &str) -> Result<usize, Error>{
+        return Ok(self.tables().login_attempts.remove(&(scope.to_string(), key.to_string())).map_or(0, |_| 1))
+    }
+
+    fn get_login_locks(&self, now: i64) -> Result<Vec<LoginAttempt>, Error>{
+        let tables = self.tables();
+        let mut locks: Vec<LoginAttempt> = tables.login_attempts.iter()
+            .filter(|(_, attempt)| attempt.locked_until > now)
+            .map(|((scope, key), attempt)| attempt.to_attempt(scope, key))
+            .collect();
+
+        locks.sort_by(|a, b| b.get_locked_until().cmp(a.get_locked_until()));
+        return Ok(locks)
+    }
+}
+
+impl MailStore for MemoryHandler{
+    fn enqueue_mail(&self, recipient: &str, subject: &str, body: &str, now: i64) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        let id = tables.outbox.keys().next_back().map_or(1, |id| id + 1);
+
+        tables.outbox.insert(id, MailRow {
+            recipient: recipient.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            status: MailStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now
+        });
+        return Ok(1)
+    }
+
+    fn claim_due_mail(&self, now: i64, lease_until: i64, limit: usize) -> Result<Vec<OutboxMail>, Error>{
+        return Ok(self.tables().outbox.iter_mut()
+            .filter(|(_, mail)| matches!(mail.status, MailStatus::Pending | MailStatus::Sending) && mail.next_attempt_at <= now)
+            .take(limit)
+            .map(|(id, mail)| {
+                mail.status = MailStatus::Sending;
+                mail.next_attempt_at = lease_until;
+                OutboxMail::new(*id, mail.recipient.clone(), mail.subject.clone(), mail.body.clone(), mail.attempts)
+            })
+            .collect())
+    }
+
+    fn mark_mail_sent(&self, id: i64, _now: i64) -> Result<usize, Error>{
+        match self.tables().outbox.get_mut(&id){
+            Some(mail) => {
+                mail.status = MailStatus::Sent;
+                mail.attempts += 1;
+                mail.body.clear();
+                return Ok(1)
+            },
+            None => return Ok(0),
+        }
+    }
+
+    fn mark_mail_failed(&self, id: i64, _error: &str, next_attempt_at: Option<i64>) -> Result<usize, Error>{
+        match self.tables().outbox.get_mut(&id){
+            Some(mail) => {
+                mail.attempts += 1;
+                mail.status = match next_attempt_at{
+                    Some(_) => MailStatus::Pending,
+                    None => MailStatus::Failed,
+                };
+                mail.next_attempt_at = next_attempt_at.unwrap_or(mail.next_attempt_at);
+
+                if next_attempt_at.is_none(){
+                    mail.body.clear();
+                }
+                return Ok(1)
+            },
+            None => return Ok(0),
+        }
+    }
+}
+
+impl MaintenanceStore for MemoryHandler{
+    fn insert_maintenance_run(&self, run: &MaintenanceRun) -> Result<usize, Error>{
+        self.tables().maintenance_runs.push(run.clone());
+        return Ok(1)
+    }
+
+    fn get_maintenance_runs(&self, job: &str, limit: usize) -> Result<Vec<MaintenanceRun>, Error>{
+        return Ok(self.tables().maintenance_runs.iter()
+            .rev()
+            .filter(|run| run.get_job() == job)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+impl RateLimitStore for MemoryHandler{
+    fn take_rate_limit_token(&self, key: &str, capacity: f64, refill_per_second: f64, now: f64) -> Result<(bool, f64), Error>{
+        let mut tables = self.tables();
+        let bucket = tables.buckets.entry(key.to_string()).or_insert(TokenBucket::full(capacity, now));
+        let allowed = bucket.take(capacity, refill_per_second, now);
+
+        return Ok((allowed, bucket.get_tokens()))
+    }
+
+    fn delete_idle_rate_limit_buckets(&self, before: f64) -> Result<usize, Error>{
+        let mut tables = self.tables();
+        let count = tables.buckets.len();
+
+        tables.buckets.retain(|_, bucket| bucket.get_updated_at() >= before);
+        return Ok(count - tables.buckets.len())
+    }
+}
+
+
+///Error SQLite reports for a violated constraint on `column`.
+fn constraint(code: i32, column: &str) -> Error{
+    return Error::SqliteFailure(ffi::Error::new(code), Some(format!("UNIQUE constraint failed: {}", column)))
+}
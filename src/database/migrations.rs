@@ -0,0 +1,362 @@
+use std::fmt;
+
+use rusqlite::{Connection, Error, Transaction};
+
+use crate::{config::cli::MigrateAction, models::database_models::DuplicateUsername, utils::time::unix_now};
+
+use super::{handler::DatabaseHandler, store::DatabaseError};
+
+
+///One step of the schema. Steps are applied in version order, each in its own transaction.
+pub struct Migration{
+    pub version: u32,
+    pub description: &'static str,
+    up: fn(&Transaction) -> Result<(), MigrationError>,
+}
+
+impl Migration{
+    pub fn apply(&self, transaction: &Transaction) -> Result<(), MigrationError>{
+        return (self.up)(transaction)
+    }
+}
+
+///Every migration known to this binary, in version order.
+///Append new steps with the next version, never change a step that was released.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "initial schema", up: initial_schema },
+    Migration { version: 2, description: "session timestamps", up: session_timestamps },
+    Migration { version: 3, description: "guest expiry", up: guest_expiry },
+    Migration { version: 4, description: "maintenance runs", up: maintenance_runs },
+    Migration { version: 5, description: "user email and verification tokens", up: user_email },
+    Migration { version: 6, description: "mail outbox", up: mail_outbox },
+    Migration { version: 7, description: "login attempts", up: login_attempts },
+    Migration { version: 8, description: "rate limit buckets", up: rate_limit_buckets },
+    Migration { version: 9, description: "display username", up: display_username },
+    Migration { version: 10, description: "password rehash flag", up: rehash_required },
+    Migration { version: 11, description: "unique usernames", up: unique_usernames },
+    Migration { version: 12, description: "drop bodies of finished mail", up: finished_mail_bodies },
+];
+
+///Schema version this binary works with.
+pub fn latest_version() -> u32{
+    return MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+
+///Reason the schema could not be brought up to date.
+#[derive(Debug)]
+pub enum MigrationError{
+    Database(DatabaseError),
+    ///The database was migrated by a newer binary. Holds its version.
+    Newer(u32),
+    ///Migrations are pending and `database.migrate_on_start` is off. Holds their number.
+    Pending(usize),
+    ///Usernames registered more than once block the unique index. Holds them with their users.
+    DuplicateUsernames(Vec<DuplicateUsername>),
+}
+
+impl fmt::Display for MigrationError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            MigrationError::Database(error) => write!(f, "{}", error),
+            MigrationError::Newer(version) => write!(f, "database schema version {} is newer than this binary supports ({}), upgrade the server", version, latest_version()),
+            MigrationError::Pending(count) => write!(f, "{} migration(s) pending, run `migrate up` first", count),
+            MigrationError::DuplicateUsernames(duplicates) => {
+                write!(f, "{} username(s) are registered more than once, remove or rename the duplicates and run `migrate up` again", duplicates.len())?;
+
+                for duplicate in duplicates{
+                    write!(f, "\n  username {} shared by users {}", duplicate.get_username(), duplicate.get_user_ids().join(", "))?;
+                }
+
+                return Ok(())
+            },
+        }
+    }
+}
+
+impl From<DatabaseError> for MigrationError{
+    fn from(error: DatabaseError) -> Self {
+        MigrationError::Database(error)
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError{
+    fn from(error: rusqlite::Error) -> Self {
+        MigrationError::Database(DatabaseError::Query(error))
+    }
+}
+
+
+///Migrations not yet applied to the database. Fails when the database is newer than this binary.
+pub fn pending(database_handler: &DatabaseHandler) -> Result<Vec<&'static Migration>, MigrationError>{
+    let version = database_handler.schema_version()?;
+
+    if version > latest_version(){
+        return Err(MigrationError::Newer(version))
+    }
+
+    return Ok(MIGRATIONS.iter().filter(|migration| migration.version > version).collect())
+}
+
+///Apply every pending migration in order. Returns the number applied.
+pub fn migrate(database_handler: &DatabaseHandler) -> Result<usize, MigrationError>{
+    let mut applied = 0;
+
+    for migration in pending(database_handler)?{
+        if database_handler.apply_migration(migration, unix_now())?{
+            println!("Applied migration {}: {}", migration.version, migration.description);
+            applied += 1;
+        }
+    }
+
+    return Ok(applied)
+}
+
+///Bring the schema up to date at startup, or only check it when `migrate_on_start` is off.
+pub fn prepare(database_handler: &DatabaseHandler, migrate_on_start: bool) -> Result<usize, MigrationError>{
+    let pending = pending(database_handler)?;
+
+    if !migrate_on_start && !pending.is_empty(){
+        return Err(MigrationError::Pending(pending.len()))
+    }
+
+    return migrate(database_handler)
+}
+
+///Run a `migrate` subcommand.
+pub fn run_command(action: MigrateAction, database_handler: &DatabaseHandler) -> Result<(), MigrationError>{
+    match action{
+        MigrateAction::Status => {
+            let version = database_handler.schema_version()?;
+            let applied = database_handler.get_applied_migrations()?;
+
+            println!("Schema version {}, this binary supports {}.", version, latest_version());
+
+            for migration in MIGRATIONS{
+                match applied.iter().find(|applied| *applied.get_version() == migration.version){
+                    Some(applied) => println!("  {:>4}  applied at {}  {}", migration.version, applied.get_applied_at(), migration.description),
+                    None if migration.version <= version => println!("  {:>4}  applied, not recorded  {}", migration.version, migration.description),
+                    None => println!("  {:>4}  pending  {}", migration.version, migration.description),
+                }
+            }
+
+            for applied in applied.iter().filter(|applied| *applied.get_version() > latest_version()){
+                println!("  {:>4}  unknown to this binary  {}", applied.get_version(), applied.get_description());
+            }
+        },
+        MigrateAction::Up => {
+            let applied = migrate(database_handler)?;
+            println!("Applied {} migration(s), schema version {}.", applied, database_handler.schema_version()?);
+        },
+    }
+
+    return Ok(())
+}
+
+
+///Version 1: users, sessions and guests as they stood before migrations were tracked.
+///Databases created before then are unversioned and may already hold later tables and columns, so every step is idempotent.
+fn initial_schema(transaction: &Transaction) -> Result<(), MigrationError>{
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS user(
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            active_sessions INTEGER DEFAULT 0,
+            salt TEXT NOT NULL
+        );",
+    ())?;
+
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS session(
+            session_id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES user(id)
+        );",
+    ())?;
+
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS guest(
+            id TEXT PRIMARY KEY,
+            session_id TEXT NOT NULL
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 2: session timestamps.
+///Sessions created before sessions expired get expires_at = 0 and are treated as expired.
+fn session_timestamps(transaction: &Transaction) -> Result<(), MigrationError>{
+    for column in ["created_at", "last_seen_at", "expires_at"]{
+        add_missing_column(transaction, "session", column, "INTEGER NOT NULL DEFAULT 0")?;
+    }
+
+    return Ok(())
+}
+
+///Version 3: guest expiry. Guests created before it was tracked are removed by the next cleanup.
+fn guest_expiry(transaction: &Transaction) -> Result<(), MigrationError>{
+    for column in ["created_at", "expires_at"]{
+        add_missing_column(transaction, "guest", column, "INTEGER NOT NULL DEFAULT 0")?;
+    }
+
+    return Ok(())
+}
+
+///Version 4: history of maintenance job runs.
+fn maintenance_runs(transaction: &Transaction) -> Result<(), MigrationError>{
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS maintenance_runs(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            job TEXT NOT NULL,
+            trigger TEXT NOT NULL,
+            started_at INTEGER NOT NULL,
+            duration_ms INTEGER NOT NULL,
+            success INTEGER NOT NULL,
+            rows_affected INTEGER,
+            attempts INTEGER NOT NULL,
+            error TEXT
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 5: user emails and their verification tokens.
+fn user_email(transaction: &Transaction) -> Result<(), MigrationError>{
+    //Accounts created before emails were collected have none, and count as verified.
+    add_missing_column(transaction, "user", "email", "TEXT")?;
+    add_missing_column(transaction, "user", "email_verified", "INTEGER NOT NULL DEFAULT 1")?;
+
+    //Emails are stored normalized, so the index catches differently cased duplicates.
+    transaction.execute("CREATE UNIQUE INDEX IF NOT EXISTS user_email ON user(email)", ())?;
+
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS verification_token(
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES user(id),
+            purpose TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 6: outgoing mail, delivered by the outbox job.
+fn mail_outbox(transaction: &Transaction) -> Result<(), MigrationError>{
+    //status is pending until delivered (sent) or out of attempts (failed).
+    //A claimed message is sending, leased until next_attempt_at so a crashed run releases it.
+    //Finished messages keep no body, see version 12
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS mail_outbox(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            recipient TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            body TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            next_attempt_at INTEGER NOT NULL,
+            sent_at INTEGER,
+            last_error TEXT
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 7: failed login counters for the lockout.
+fn login_attempts(transaction: &Transaction) -> Result<(), MigrationError>{
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS login_attempts(
+            scope TEXT NOT NULL,
+            key TEXT NOT NULL,
+            failures INTEGER NOT NULL DEFAULT 0,
+            last_failure_at INTEGER NOT NULL DEFAULT 0,
+            locked_until INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (scope, key)
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 8: token buckets shared by every instance on the database.
+fn rate_limit_buckets(transaction: &Transaction) -> Result<(), MigrationError>{
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS rate_limit_buckets(
+            key TEXT PRIMARY KEY,
+            tokens REAL NOT NULL,
+            updated_at REAL NOT NULL,
+            allowed INTEGER NOT NULL
+        );",
+    ())?;
+
+    return Ok(())
+}
+
+///Version 9: username as typed, the username column holds the keyed index.
+fn display_username(transaction: &Transaction) -> Result<(), MigrationError>{
+    return Ok(add_missing_column(transaction, "user", "display_username", "TEXT")?)
+}
+
+///Version 10: flag for passwords to rehash at the next login.
+fn rehash_required(transaction: &Transaction) -> Result<(), MigrationError>{
+    return Ok(add_missing_column(transaction, "user", "rehash_required", "INTEGER NOT NULL DEFAULT 0")?)
+}
+
+///Version 11: one user per username. Databases from before the index may hold duplicates, which have to be resolved by hand.
+fn unique_usernames(transaction: &Transaction) -> Result<(), MigrationError>{
+    let duplicates = get_duplicate_usernames(transaction)?;
+
+    if !duplicates.is_empty(){
+        return Err(MigrationError::DuplicateUsernames(duplicates))
+    }
+
+    transaction.execute("CREATE UNIQUE INDEX IF NOT EXISTS user_username ON user(username)", ())?;
+
+    return Ok(())
+}
+
+///Usernames shared by more than one user, with the ids of those users.
+fn get_duplicate_usernames(connection: &Connection) -> Result<Vec<DuplicateUsername>, Error>{
+    let mut statement = connection.prepare(
+        "SELECT username, group_concat(id, ',') FROM user GROUP BY username HAVING COUNT(*) > 1"
+    )?;
+
+    let duplicates = statement.query_map((), |row| {
+        let ids: String = row.get(1)?;
+
+        Ok(DuplicateUsername::new(
+            row.get(0)?,
+            ids.split(',').map(|id| id.to_string()).collect()
+        ))
+    })?;
+
+    return duplicates.collect()
+}
+
+///Version 12: bodies of sent and failed mail are emptied. They may hold verification, reset or unlock tokens,
+///which the database otherwise only keeps hashed.
+fn finished_mail_bodies(transaction: &Transaction) -> Result<(), MigrationError>{
+    transaction.execute("UPDATE mail_outbox SET body = '' WHERE status IN ('sent', 'failed')", ())?;
+
+    return Ok(())
+}
+
+///Add a column to an existing table, unless it is already present.
+fn add_missing_column(connection: &Connection, table: &str, column: &str, definition: &str) -> Result<(), Error>{
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement
+        .query_map((), |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, Error>>()?;
+    let exists = columns.iter().any(|name| name == column);
+
+    if !exists{
+        connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), ())?;
+    }
+
+    return Ok(())
+}
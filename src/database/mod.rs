@@ -1 +1,8 @@
-pub mod handler;
\ No newline at end of file
+pub mod handler;
+pub mod memory;
+pub mod migrations;
+pub mod pool;
+pub mod store;
+
+#[cfg(test)]
+mod tests;
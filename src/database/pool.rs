@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OpenFlags;
+
+use crate::config::server_config::DatabaseSettings;
+
+use super::{handler::DatabaseHandler, store::{Backend, DatabaseError}};
+
+
+///Pool of connections to the database file, shared by handlers, maintenance jobs and stores.
+///Connections run in WAL mode, so readers are not blocked by a writer, and wait on locks for the busy timeout.
+#[derive(Clone)]
+pub struct Database{
+    pool: Pool<SqliteConnectionManager>
+}
+
+impl Database{
+    ///Open the pool. One connection is opened right away, so a broken database fails at startup.
+    pub fn open(settings: &DatabaseSettings) -> Result<Database, DatabaseError>{
+        let busy_timeout = Duration::from_millis(settings.busy_timeout_ms);
+        let statement_cache_size = settings.statement_cache_size;
+
+        let manager = SqliteConnectionManager::file(&settings.path).with_init(move |connection| {
+            connection.busy_timeout(busy_timeout)?;
+            connection.set_prepared_statement_cache_capacity(statement_cache_size);
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            return Ok(())
+        });
+
+        let pool = Pool::builder()
+            .max_size(settings.pool_size)
+            .min_idle(Some(1))
+            .connection_timeout(busy_timeout)
+            .build(manager)?;
+
+        return Ok(Database { pool: pool })
+    }
+
+    ///Single read-only connection for inspecting the database. Nothing is created or changed, not even the journal mode.
+    pub fn open_read_only(settings: &DatabaseSettings) -> Result<Database, DatabaseError>{
+        let busy_timeout = Duration::from_millis(settings.busy_timeout_ms);
+        let manager = SqliteConnectionManager::file(&settings.path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_init(move |connection| connection.busy_timeout(busy_timeout));
+
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(busy_timeout)
+            .build(manager)?;
+
+        return Ok(Database { pool: pool })
+    }
+
+    ///Handler on a pooled connection, for code that already runs off the request threads.
+    pub fn handler(&self) -> Result<DatabaseHandler, DatabaseError>{
+        return Ok(DatabaseHandler::new(self.pool.get()?))
+    }
+}
+
+impl Backend for Database{
+    type Store = DatabaseHandler;
+
+    fn with<T>(&self, task: impl FnOnce(&DatabaseHandler) -> Result<T, DatabaseError>) -> Result<T, DatabaseError>{
+        return task(&self.handler()?)
+    }
+}
@@ -0,0 +1,232 @@
+use std::{fmt, future::Future};
+
+use actix_web::{error::BlockingError, web};
+use argon2::password_hash::SaltString;
+use rusqlite::Error;
+use uuid::Uuid;
+
+use crate::models::database_models::{Guest, LoginAttempt, MaintenanceRun, OutboxMail, Session, User};
+
+
+///Failure of store work: no free connection, a failed query, or a lost blocking task.
+///Every store reports failed operations as SQLite would, so callers handle the backends alike.
+#[derive(Debug)]
+pub enum DatabaseError{
+    Pool(r2d2::Error),
+    Query(Error),
+    Blocking(BlockingError),
+}
+
+impl fmt::Display for DatabaseError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DatabaseError::Pool(error) => write!(f, "no database connection: {}", error),
+            DatabaseError::Query(error) => write!(f, "{}", error),
+            DatabaseError::Blocking(error) => write!(f, "database task failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError{}
+
+impl From<r2d2::Error> for DatabaseError{
+    fn from(error: r2d2::Error) -> Self {
+        DatabaseError::Pool(error)
+    }
+}
+
+impl From<Error> for DatabaseError{
+    fn from(error: Error) -> Self {
+        DatabaseError::Query(error)
+    }
+}
+
+
+///Where stores come from: the SQLite connection pool, or memory.
+///Handlers are generic over the backend and only reach storage through it.
+pub trait Backend: Clone + Send + Sync + 'static{
+    type Store: Store;
+
+    ///Run store work on the calling thread, for code already running off the request threads.
+    fn with<T>(&self, task: impl FnOnce(&Self::Store) -> Result<T, DatabaseError>) -> Result<T, DatabaseError>;
+
+    ///Run store work without holding up a request thread. By default on the blocking thread pool.
+    fn run<T, F>(&self, task: F) -> impl Future<Output = Result<T, DatabaseError>> + Send
+    where
+        F: FnOnce(&Self::Store) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let backend = self.clone();
+
+        return async move { web::block(move || backend.with(task)).await.map_err(DatabaseError::Blocking)? }
+    }
+}
+
+///Every store the server uses.
+pub trait Store: UserStore + TokenStore + SessionStore + GuestStore + LoginAttemptStore + MailStore + MaintenanceStore + RateLimitStore{}
+
+impl<S> Store for S where S: UserStore + TokenStore + SessionStore + GuestStore + LoginAttemptStore + MailStore + MaintenanceStore + RateLimitStore{}
+
+
+///Registered users.
+pub trait UserStore{
+    ///Check if a username hash is already registered.
+    fn username_exists(&self, username: &str) -> Result<bool, Error>;
+
+    ///Get all users with matching username.
+    fn get_users(&self, username: &str) -> Result<Vec<User>, Error>;
+
+    ///Get every user, oldest id first.
+    fn list_users(&self) -> Result<Vec<User>, Error>;
+
+    ///Rewrite the username index of a user still stored under `legacy`.
+    ///Returns 0 when the row was already migrated.
+    fn migrate_username(&self, user_id: &Uuid, legacy: &str, index: &str, display_username: Option<&str>) -> Result<usize, Error>;
+
+    ///Check if user/session id generated exists in database.
+    fn id_exists(&self, target: &str, id: &Uuid) -> Result<bool, Error>;
+
+    ///Insert new user to database.
+    fn insert_user(&self, user: User) -> Result<usize, Error>;
+
+    ///Get user with matching id.
+    fn get_user_from_id(&self, user_id: &Uuid) -> Result<Option<User>, Error>;
+
+    ///Replace a password hash with one of the same password made with current settings, clearing the rehash flag.
+    ///Returns 0 when the stored hash changed meanwhile, e.g. by a concurrent password change.
+    fn rehash_password(&self, user_id: &Uuid, stored: &str, password: &str, salt: &SaltString) -> Result<usize, Error>;
+
+    ///Ids and salts of the users not flagged for a rehash yet.
+    fn get_unflagged_salts(&self) -> Result<Vec<(String, String)>, Error>;
+
+    ///Flag users for a new password hash at their next login.
+    fn flag_rehash_required(&self, user_ids: &[String]) -> Result<usize, Error>;
+
+    ///Replace the password of a user. With `keep_session` set, every other session of the user is revoked.
+    ///Returns the number of revoked sessions.
+    fn change_password(&self, user_id: &Uuid, password: &str, salt: &SaltString, keep_session: Option<&Uuid>) -> Result<usize, Error>;
+
+    ///Get user with matching normalized email address.
+    fn get_user_from_email(&self, email: &str) -> Result<Option<User>, Error>;
+
+    ///Check if a normalized email address is already registered.
+    fn email_exists(&self, email: &str) -> Result<bool, Error>;
+}
+
+///Single-use tokens mailed for email verification, password resets and account unlocks.
+pub trait TokenStore{
+    ///Store a verification token. Only the token hash is kept.
+    fn insert_verification_token(&self, token_hash: &str, user_id: &Uuid, purpose: &str, created_at: i64, expires_at: i64) -> Result<usize, Error>;
+
+    ///Delete every token of a purpose issued to a user, e.g. older reset links once a new one is sent.
+    fn delete_verification_tokens(&self, user_id: &Uuid, purpose: &str) -> Result<usize, Error>;
+
+    ///User a verification token was issued to, if it is still valid. The token is not used up.
+    fn find_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>;
+
+    ///Use up a verification token and return the user it was issued to.
+    fn consume_verification_token(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>;
+
+    ///Use up a password reset token: replace the password of its user, revoke all of the user's sessions and lift any account lock.
+    ///The address the token was mailed to counts as confirmed. Returns the number of revoked sessions,
+    ///or None when the token is no longer valid for `user_id`.
+    fn reset_password(&self, token_hash: &str, purpose: &str, now: i64, user_id: &Uuid, password: &str, salt: &SaltString) -> Result<Option<usize>, Error>;
+
+    ///Use up an email verification token and mark the address of its user as confirmed.
+    ///Returns the user the token was issued to, if it was valid.
+    fn confirm_email(&self, token_hash: &str, purpose: &str, now: i64) -> Result<Option<Uuid>, Error>;
+}
+
+///Sessions of registered users.
+pub trait SessionStore{
+    ///Get session with matching id.
+    fn get_session_from_id(&self, session_id: &Uuid) -> Result<Option<Session>, Error>;
+
+    ///Insert new session to database.
+    fn insert_session(&self, session: &Session) -> Result<usize, Error>;
+
+    ///Record activity on a session.
+    fn touch_session(&self, session_id: &Uuid, last_seen_at: i64) -> Result<usize, Error>;
+
+    ///Delete a session belonging to the given user.
+    fn delete_session(&self, session_id: &Uuid, user_id: &Uuid) -> Result<usize, Error>;
+
+    ///Delete every session belonging to the given user.
+    fn delete_user_sessions(&self, user_id: &Uuid) -> Result<usize, Error>;
+}
+
+///Guests, visitors holding a session without an account.
+pub trait GuestStore{
+    ///Insert new guest user to database. The session's user id is the guest id.
+    fn insert_guest(&self, guest_session: &Session) -> Result<usize, Error>;
+
+    ///Move a guest to a new session id and expiry. Only updates the guest if it still holds `old_session_id`.
+    fn renew_guest(&self, old_session_id: &Uuid, guest_session: &Session) -> Result<usize, Error>;
+
+    ///Get guest with matching id.
+    fn get_guest(&self, guest_id: &Uuid) -> Result<Option<Guest>, Error>;
+
+    ///Turn a guest into a registered user in one transaction.
+    ///Inserts the user and its session, then removes the guest row.
+    fn upgrade_guest(&self, user: User, session: &Session) -> Result<usize, Error>;
+
+    ///Delete a guest, only while it still holds `session_id`.
+    fn delete_guest(&self, guest_id: &Uuid, session_id: &Uuid) -> Result<usize, Error>;
+
+    ///Delete guests whose expiry has passed, along with any session rows they own.
+    ///Returns the number of guest and session rows removed.
+    fn delete_expired_guests(&self, now: i64) -> Result<(usize, usize), Error>;
+}
+
+///Failed login counters and locks of accounts and addresses.
+pub trait LoginAttemptStore{
+    ///Get the failed login counter of an account or address.
+    fn get_login_attempt(&self, scope: &str, key: &str) -> Result<Option<LoginAttempt>, Error>;
+
+    ///Count a failed login. The count restarts when the previous failure is older than `window_seconds`.
+    fn record_login_failure(&self, scope: &str, key: &str, now: i64, window_seconds: i64) -> Result<LoginAttempt, Error>;
+
+    ///Lock an account or address until `locked_until`. The failure count starts over once the lock ends.
+    fn lock_login(&self, scope: &str, key: &str, locked_until: i64) -> Result<usize, Error>;
+
+    ///Forget the failed logins of an account or address, lifting any lock.
+    fn clear_login_attempts(&self, scope: &str, key: &str) -> Result<usize, Error>;
+
+    ///Accounts and addresses locked at `now`.
+    fn get_login_locks(&self, now: i64) -> Result<Vec<LoginAttempt>, Error>;
+}
+
+///Outbox of mail waiting for the delivery job.
+pub trait MailStore{
+    ///Queue a message for the mail delivery job.
+    fn enqueue_mail(&self, recipient: &str, subject: &str, body: &str, now: i64) -> Result<usize, Error>;
+
+    ///Claim messages due for delivery, oldest first, leasing them until `lease_until`.
+    ///Pending messages and messages whose lease ran out are due. A claimed message is not handed out again while leased.
+    fn claim_due_mail(&self, now: i64, lease_until: i64, limit: usize) -> Result<Vec<OutboxMail>, Error>;
+
+    ///Record a delivered message. Its body is dropped, it may hold a live token.
+    fn mark_mail_sent(&self, id: i64, now: i64) -> Result<usize, Error>;
+
+    ///Record a failed delivery attempt. Without a next attempt the message is given up on and its body dropped.
+    fn mark_mail_failed(&self, id: i64, error: &str, next_attempt_at: Option<i64>) -> Result<usize, Error>;
+}
+
+///History of maintenance job runs.
+pub trait MaintenanceStore{
+    ///Record the outcome of a maintenance job run.
+    fn insert_maintenance_run(&self, run: &MaintenanceRun) -> Result<usize, Error>;
+
+    ///Most recent runs of a maintenance job, newest first.
+    fn get_maintenance_runs(&self, job: &str, limit: usize) -> Result<Vec<MaintenanceRun>, Error>;
+}
+
+///Token buckets of the shared rate limit store.
+pub trait RateLimitStore{
+    ///Take a token from a rate limit bucket.
+    ///The bucket is refilled for the time since its last use first. Returns whether a token was taken and the tokens left.
+    fn take_rate_limit_token(&self, key: &str, capacity: f64, refill_per_second: f64, now: f64) -> Result<(bool, f64), Error>;
+
+    ///Delete rate limit buckets unused since `before`. A bucket left alone long enough is full, the same as no bucket.
+    fn delete_idle_rate_limit_buckets(&self, before: f64) -> Result<usize, Error>;
+}
@@ -0,0 +1,113 @@
+use std::{env, fs};
+
+use rusqlite::Connection;
+
+use crate::config::server_config::DatabaseSettings;
+
+use super::{migrations::{latest_version, migrate, pending, prepare, MigrationError, MIGRATIONS}, pool::Database};
+
+
+///Database file in the temp directory, removed with its journal when dropped.
+struct DatabaseFile(DatabaseSettings);
+
+impl DatabaseFile{
+    fn new(name: &str) -> Self{
+        let path = env::temp_dir().join(format!("almc-{}-{}.db3", name, std::process::id()));
+        let file = DatabaseFile(DatabaseSettings { path: path.display().to_string(), ..DatabaseSettings::default() });
+        file.remove();
+        return file
+    }
+
+    fn open(&self) -> Database{
+        return Database::open(&self.0).expect("database")
+    }
+
+    ///Connection outside the pool, for setting up and inspecting the file directly.
+    fn connect(&self) -> Connection{
+        return Connection::open(&self.0.path).expect("connection")
+    }
+
+    fn remove(&self){
+        for suffix in ["", "-wal", "-shm"]{
+            let _ = fs::remove_file(format!("{}{}", self.0.path, suffix));
+        }
+    }
+}
+
+impl Drop for DatabaseFile{
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+
+#[test]
+fn migrations_bring_a_new_database_up_to_date(){
+    let file = DatabaseFile::new("migrate-new");
+    let handler = file.open().handler().expect("handler");
+
+    assert_eq!(pending(&handler).expect("pending").len(), MIGRATIONS.len());
+    assert_eq!(migrate(&handler).expect("migrate"), MIGRATIONS.len());
+    assert_eq!(handler.schema_version().expect("version"), latest_version());
+
+    let applied = handler.get_applied_migrations().expect("applied");
+    assert_eq!(applied.iter().map(|migration| *migration.get_version()).collect::<Vec<u32>>(), MIGRATIONS.iter().map(|migration| migration.version).collect::<Vec<u32>>());
+
+    //Nothing left to do
+    assert!(pending(&handler).expect("pending").is_empty());
+    assert_eq!(migrate(&handler).expect("migrate"), 0);
+}
+
+#[test]
+fn pending_migrations_wait_when_not_migrating_on_start(){
+    let file = DatabaseFile::new("migrate-pending");
+    let handler = file.open().handler().expect("handler");
+
+    assert!(matches!(prepare(&handler, false), Err(MigrationError::Pending(count)) if count == MIGRATIONS.len()));
+    assert_eq!(handler.schema_version().expect("version"), 0);
+
+    assert_eq!(prepare(&handler, true).expect("prepare"), MIGRATIONS.len());
+    assert_eq!(prepare(&handler, false).expect("prepare"), 0);
+}
+
+#[test]
+fn newer_schema_is_refused(){
+    let file = DatabaseFile::new("migrate-newer");
+    file.connect().pragma_update(None, "user_version", latest_version() + 1).expect("version");
+    let handler = file.open().handler().expect("handler");
+
+    assert!(matches!(pending(&handler), Err(MigrationError::Newer(version)) if version == latest_version() + 1));
+    assert!(matches!(migrate(&handler), Err(MigrationError::Newer(_))));
+    assert!(matches!(prepare(&handler, true), Err(MigrationError::Newer(_))));
+}
+
+#[test]
+fn duplicate_usernames_stop_before_the_unique_index(){
+    let file = DatabaseFile::new("migrate-duplicates");
+
+    //An unversioned database from before migrations, with one username registered twice
+    let connection = file.connect();
+    connection.execute_batch(
+        "CREATE TABLE user(id TEXT PRIMARY KEY, username TEXT NOT NULL, password TEXT NOT NULL, active_sessions INTEGER DEFAULT 0, salt TEXT NOT NULL);
+        INSERT INTO user (id, username, password, salt) VALUES ('a', 'twice', '', ''), ('b', 'twice', '', ''), ('c', 'once', '', '');"
+    ).expect("legacy schema");
+
+    let handler = file.open().handler().expect("handler");
+
+    match migrate(&handler){
+        Err(MigrationError::DuplicateUsernames(duplicates)) => {
+            assert_eq!(duplicates.len(), 1);
+            assert_eq!(duplicates[0].get_username(), "twice");
+            assert_eq!(duplicates[0].get_user_ids().len(), 2);
+        },
+        other => panic!("expected duplicate usernames, got {:?}", other),
+    }
+
+    //The failed step left the database at the version before it
+    assert_eq!(handler.schema_version().expect("version"), 10);
+
+    connection.execute("DELETE FROM user WHERE id = 'b'", ()).expect("resolve duplicate");
+    assert_eq!(migrate(&handler).expect("migrate"), 2);
+    assert_eq!(handler.schema_version().expect("version"), latest_version());
+    assert!(connection.execute("INSERT INTO user (id, username, password, salt) VALUES ('d', 'once', '', '')", ()).is_err());
+}
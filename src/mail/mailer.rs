@@ -0,0 +1,116 @@
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use lettre::{message::{header::ContentType, Mailbox}, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
+use uuid::Uuid;
+
+use crate::{config::server_config::{MailSettings, MailTransport, SmtpSecurity, SmtpSettings}, utils::time::unix_now};
+
+
+///Delivers a single message. Returns a description of the failure when the message was not accepted.
+pub trait Mailer: Send + Sync{
+    fn send(&self, message: &Message) -> Result<(), String>;
+}
+
+///Build the mailer selected by the configuration.
+pub fn from_settings(settings: &MailSettings) -> Result<Arc<dyn Mailer>, String>{
+    match settings.transport{
+        MailTransport::Stdout => return Ok(Arc::new(StdoutMailer { show_body: settings.stdout_show_body })),
+        MailTransport::File => return Ok(Arc::new(FileMailer::new(&settings.outbox_dir)?)),
+        MailTransport::Smtp => return Ok(Arc::new(SmtpMailer::new(&settings.smtp)?)),
+    }
+}
+
+///Build a plain text message from the configured sender.
+pub fn compose(from: &str, recipient: &str, subject: &str, body: &str) -> Result<Message, String>{
+    let from: Mailbox = from.parse().map_err(|error| format!("invalid sender: {}", error))?;
+    let to: Mailbox = recipient.parse().map_err(|error| format!("invalid recipient: {}", error))?;
+
+    return Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body.to_string())
+        .map_err(|error| error.to_string())
+}
+
+
+///Prints messages to standard output. For development.
+///Bodies carry verification and reset tokens, so only the headers are printed unless `show_body` is set.
+pub struct StdoutMailer{
+    show_body: bool
+}
+
+impl Mailer for StdoutMailer{
+    fn send(&self, message: &Message) -> Result<(), String> {
+        let printed = match self.show_body{
+            true => String::from_utf8_lossy(&message.formatted()).to_string(),
+            false => format!("{}\r\n[body redacted, set mail.stdout_show_body to print it]", message.headers()),
+        };
+
+        println!("----- Outgoing mail -----\n{}\n-------------------------", printed);
+        return Ok(())
+    }
+}
+
+
+///Writes each message to its own `.eml` file in a directory. For development and tests.
+pub struct FileMailer{
+    directory: PathBuf
+}
+
+impl FileMailer{
+    pub fn new(directory: &str) -> Result<Self, String>{
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory).map_err(|error| format!("cannot create {}: {}", directory.display(), error))?;
+
+        return Ok(FileMailer { directory: directory })
+    }
+}
+
+impl Mailer for FileMailer{
+    fn send(&self, message: &Message) -> Result<(), String> {
+        let path = self.directory.join(format!("{}-{}.eml", unix_now(), Uuid::new_v4()));
+        return fs::write(&path, message.formatted()).map_err(|error| format!("cannot write {}: {}", path.display(), error))
+    }
+}
+
+
+///Relays messages through an SMTP server.
+pub struct SmtpMailer{
+    transport: SmtpTransport
+}
+
+impl SmtpMailer{
+    pub fn new(settings: &SmtpSettings) -> Result<Self, String>{
+        let builder = match settings.security{
+            SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&settings.host).map_err(|error| error.to_string())?,
+            SmtpSecurity::Tls => SmtpTransport::relay(&settings.host).map_err(|error| error.to_string())?,
+            SmtpSecurity::None => SmtpTransport::builder_dangerous(&settings.host),
+        };
+
+        let port = settings.port.unwrap_or(match settings.security{
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+        });
+
+        let mut builder = builder
+            .port(port)
+            .timeout(Some(Duration::from_secs(settings.timeout_seconds)));
+
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password){
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        return Ok(SmtpMailer { transport: builder.build() })
+    }
+}
+
+impl Mailer for SmtpMailer{
+    fn send(&self, message: &Message) -> Result<(), String> {
+        return self.transport.send(message)
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use rusqlite::Error;
+
+use crate::{config::server_config::MailSettings, database::store::{Backend, MailStore}, utils::time::unix_now};
+
+use super::mailer::{compose, Mailer};
+
+///Messages handed to the mailer per run of the delivery job.
+const BATCH_SIZE: usize = 50;
+
+
+///Queue a message. It is sent by the next run of the mail delivery job, so handlers never wait on the relay.
+pub fn enqueue(database_handler: &impl MailStore, recipient: &str, subject: &str, body: &str) -> Result<usize, Error>{
+    return database_handler.enqueue_mail(recipient, subject, body, unix_now())
+}
+
+///Mail delivery job.
+///Claims due messages from the outbox, sends them and reschedules failed ones with exponential backoff,
+///giving up after the configured number of attempts. Reports how many messages were sent.
+pub fn deliver_mail<B: Backend>(database: B, mailer: Arc<dyn Mailer>, settings: &MailSettings) -> Result<usize, String> {
+    //The batch is leased for as long as sending it may take, a later run only retakes it after a crash
+    let now = unix_now();
+    let lease_until = now + (settings.smtp.timeout_seconds as i64).saturating_mul(BATCH_SIZE as i64);
+
+    //The connection goes back to the pool while sending, a slow relay must not hold it
+    let due = database.with(|handler| Ok(handler.claim_due_mail(now, lease_until, BATCH_SIZE)?))
+        .map_err(|error| error.to_string())?;
+
+    let mut sent = 0;
+    let mut failed = 0;
+
+    for mail in due{
+        let outcome = compose(&settings.from, mail.get_recipient(), mail.get_subject(), mail.get_body())
+            .and_then(|message| mailer.send(&message));
+
+        let recorded = match outcome{
+            Ok(()) => {
+                sent += 1;
+                database.with(|handler| Ok(handler.mark_mail_sent(*mail.get_id(), unix_now())?))
+            },
+            Err(error) => {
+                failed += 1;
+                let attempts = *mail.get_attempts() as u32 + 1;
+                let next_attempt_at = match attempts < settings.max_attempts{
+                    true => {
+                        let backoff = settings.retry_backoff_seconds.saturating_mul(1 << (attempts - 1).min(16));
+                        Some(unix_now() + backoff as i64)
+                    },
+                    false => None,
+                };
+
+                println!("Mail {} to {} failed (attempt {}): {}", mail.get_id(), mail.get_recipient(), attempts, error);
+                database.with(|handler| Ok(handler.mark_mail_failed(*mail.get_id(), &error, next_attempt_at)?))
+            },
+        };
+
+        if let Err(error) = recorded{
+            return Err(error.to_string())
+        }
+    }
+
+    if failed > 0{
+        println!("Mail delivery sent {} message(s), {} failed.", sent, failed);
+    }
+
+    return Ok(sent)
+}
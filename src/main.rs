@@ -1,96 +1,455 @@
-use std::sync::{Arc, Mutex};
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+use std::sync::Arc;
 
 use actix_session::{config::{BrowserSession, CookieContentSecurity}, storage::CookieSessionStore, SessionMiddleware};
-use actix_web::{cookie::Key, guard, middleware::Logger, web, App, HttpServer};
+use actix_web::{cookie::Key, guard, middleware::{from_fn, Logger}, web, App, HttpServer};
+use clap::Parser;
+use futures_util::future::try_join;
 
-use auth::credentials::guest_credentials;
-use database::handler::DatabaseHandler;
+use admin::{jobs::{job_history, list_jobs, trigger_job}, lockouts::{list_lockouts, unlock}, users::list_users};
+use auth::{credentials::{guest_credentials, upgrade_guest, username_available}, key_ring::{reseal_session_cookie, KeyRing}, lockout::unlock_account, logout::{logout, logout_all}, password::{change_password, forgot_password, reset_password}, pepper::Peppers, profile::me, usernames::UsernameKeys, verification::verify_email};
+use config::{cli::{Cli, Command, MigrateAction}, server_config::{CookieSettings, DatabaseBackend, ServerConfig}};
+use database::{memory::MemoryDatabase, migrations::{self, MigrationError}, pool::Database, store::Backend};
+use mail::{mailer::{self, Mailer}, outbox::deliver_mail};
 use maintenance::maintainer::Maintainer;
+use rate_limit::limiter::RateLimiter;
+use tls::{certificates::CertificateStore, redirect::{redirect_to_https, HttpsRedirect}};
+use utils::{client_ip::TrustedProxies, payload::{form_config, json_config, payload_config}, request_id::request_id};
 
 use crate::auth::credentials::{verify_credentials, save_credentials};
-use crate::maintenance::maintainer::guest_cleanup;
+use crate::maintenance::maintainer::{flag_legacy_salts, guest_cleanup};
 
+mod admin;
+mod config;
 mod database;
 mod models;
 mod auth;
+mod mail;
 mod maintenance;
+mod rate_limit;
+mod tls;
+mod utils;
 
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()>{
     
-    //Initialize database handler
-    let handler_op = DatabaseHandler::new();
+    //Load configuration: file, then environment, then command line flags
+    let cli = Cli::parse();
+    let config = match ServerConfig::load(&cli){
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Configuration error: {}", error);
+            std::process::exit(2);
+        },
+    };
 
-    if handler_op.is_ok(){
-        let handler = Arc::new(Mutex::new(handler_op.unwrap()));
+    //Commands run against the database and exit without starting the server
+    if let Some(Command::Migrate { action }) = cli.command{
+        //Status only reads, so it must not create the file, tables or WAL journal
+        let opened = match action{
+            MigrateAction::Status => Database::open_read_only(&config.database),
+            MigrateAction::Up => Database::open(&config.database),
+        };
 
-        match handler.lock().unwrap().initialize_tables(){
-            Ok(_) => {
-                println!("Initialized database tables...")
-            },
+        let result = opened
+            .and_then(|database| database.handler())
+            .map_err(MigrationError::from)
+            .and_then(|handler| migrations::run_command(action, &handler));
+
+        match result{
+            Ok(_) => std::process::exit(0),
             Err(error) => {
-                panic!("Error running database. {:?}", error);
+                eprintln!("Migration error: {}", error);
+                std::process::exit(1);
             },
         }
+    }
+
+    //Load session cookie keys
+    let key_ring = match KeyRing::load(&config.cookie){
+        Ok(ring) => web::Data::new(ring),
+        Err(error) => {
+            eprintln!("Session key error: {}", error);
+            std::process::exit(2);
+        },
+    };
+
+    //Load the username index key
+    let username_keys = match UsernameKeys::load(&config.username){
+        Ok(keys) => web::Data::new(keys),
+        Err(error) => {
+            eprintln!("Username key error: {}", error);
+            std::process::exit(2);
+        },
+    };
+
+    //Load the password peppers
+    let peppers = match Peppers::load(&config.pepper){
+        Ok(peppers) => web::Data::new(peppers),
+        Err(error) => {
+            eprintln!("Password pepper error: {}", error);
+            std::process::exit(2);
+        },
+    };
+
+    //Outbound mail transport
+    let mailer = match mailer::from_settings(&config.mail){
+        Ok(mailer) => mailer,
+        Err(error) => {
+            eprintln!("Mail transport error: {}", error);
+            std::process::exit(2);
+        },
+    };
+
+    match config.database.backend{
+        DatabaseBackend::Sqlite => {
+            let database = open_database(&config);
+            return serve(config, database, key_ring, username_keys, peppers, mailer).await
+        },
+        DatabaseBackend::Memory => {
+            println!("Warning: using the memory database backend, all data is lost on exit.");
+            return serve(config, MemoryDatabase::default(), key_ring, username_keys, peppers, mailer).await
+        },
+    }
+}
+
+///Open the database connection pool and bring the schema up to date.
+///Exits when the schema cannot be used by this binary.
+fn open_database(config: &ServerConfig) -> Database{
+    //Open the database connection pool
+    let database = match Database::open(&config.database){
+        Ok(database) => database,
+        Err(error) => {
+            panic!("Error opening database. {:?}", error);
+        },
+    };
+
+    let handler = match database.handler(){
+        Ok(handler) => handler,
+        Err(error) => {
+            panic!("Error opening database. {:?}", error);
+        },
+    };
+
+    //Bring the schema up to date, a database from a newer release is never touched
+    match migrations::prepare(&handler, config.database.migrate_on_start){
+        Ok(_) => {
+            println!("Database schema at version {}...", migrations::latest_version())
+        },
+        Err(error @ (MigrationError::Newer(_) | MigrationError::Pending(_) | MigrationError::DuplicateUsernames(_))) => {
+            eprintln!("Database schema error: {}", error);
+            std::process::exit(2);
+        },
+        Err(error) => {
+            panic!("Error migrating database. {:?}", error);
+        },
+    }
 
-        let maintainer = Maintainer::new().await;
-        
-        let res = maintainer.schedule_task({
-            move || guest_cleanup(handler.clone()) // Pass the Arc<Mutex<DatabaseHandler>> to the task
+    //The startup connection goes back to the pool
+    drop(handler);
+
+    return database
+}
+
+///Register the maintenance jobs and run the server on `database` until it stops.
+async fn serve<B: Backend>(
+    config: ServerConfig,
+    database: B,
+    key_ring: web::Data<KeyRing>,
+    username_keys: web::Data<UsernameKeys>,
+    peppers: web::Data<Peppers>,
+    mailer: Arc<dyn Mailer>,
+) -> std::io::Result<()>{
+    //Register maintenance jobs
+    let maintainer = Maintainer::new(database.clone()).await;
+    
+    let mut res = maintainer.register("guest_cleanup", &config.maintainer.guest_cleanup, {
+        let database = database.clone();
+        move || guest_cleanup(database.clone())
+    }).await;
+
+    if res.is_ok(){
+        res = maintainer.register("mail_delivery", &config.maintainer.mail_delivery, {
+            let database = database.clone();
+            let settings = config.mail.clone();
+            move || deliver_mail(database.clone(), mailer.clone(), &settings)
+        }).await;
+    }
+
+    if res.is_ok(){
+        res = maintainer.register("legacy_salts", &config.maintainer.legacy_salts, {
+            let database = database.clone();
+            move || flag_legacy_salts(database.clone())
         }).await;
-        
-        if res.is_ok(){
+    }
+    
+    match res{
+        Ok(_) => {
             println!("Initialized maintainer...");
+            let scheduled = maintainer.clone();
             tokio::spawn(async move {
-                maintainer.start().await;
+                scheduled.start().await;
             });
-        }
-        
+        },
+        Err(error) => {
+            eprintln!("Error initializing maintainer: {:?}", error);
+        },
     }
 
     println!("Starting server...");
+
+    let cookie_settings = config.cookie.clone();
+    let listeners = config.server.listeners.clone();
+    let tls_settings = config.server.tls.clone();
+    let public_host = config.server.public_host.clone();
+    let trusted_proxies = TrustedProxies::parse(&config.server.trusted_proxies).unwrap_or_default();
+    let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit, trusted_proxies.clone(), &database));
+    let blocking_threads = config.database.blocking_threads;
+    let max_body_bytes = config.server.max_body_bytes;
+    let proxies_data = web::Data::new(trusted_proxies);
+    let config_data = web::Data::new(config);
+    let maintainer_data = web::Data::new(maintainer);
+    let database_data = web::Data::new(database);
     
-    HttpServer::new(||{
+    let mut server = HttpServer::new(move ||{
         App::new()
-            .wrap(Logger::default())
-            .wrap(cookie_handler())
+            .app_data(config_data.clone())
+            .app_data(database_data.clone())
+            .app_data(key_ring.clone())
+            .app_data(username_keys.clone())
+            .app_data(peppers.clone())
+            .app_data(maintainer_data.clone())
+            .app_data(proxies_data.clone())
+            .app_data(json_config(max_body_bytes))
+            .app_data(form_config(max_body_bytes))
+            .app_data(payload_config(max_body_bytes))
+            .wrap(cookie_handler(&cookie_settings, key_ring.current()))
+            .wrap(from_fn(reseal_session_cookie))
+            .wrap(from_fn(request_id))
+            //Default format with the request id, which error bodies quote
+            .wrap(Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#))
             .service(
-                web::resource("/verify").route(
+                web::resource("/verify").wrap(rate_limiter.limit("/verify")).route(
                 web::route()
                     .guard(guard::Post())
-                    .to(verify_credentials)
+                    .to(verify_credentials::<B>)
+                )
+            )
+            .service(
+                web::resource("/sanitize").wrap(rate_limiter.limit("/sanitize")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(save_credentials::<B>)    
+                )
+            )
+            .service(
+                web::resource("/guest").wrap(rate_limiter.limit("/guest")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(guest_credentials::<B>)
+                )
+            )
+            .service(
+                web::resource("/guest/upgrade").wrap(rate_limiter.limit("/guest/upgrade")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(upgrade_guest::<B>)
+                )
+            )
+            .service(
+                web::resource("/username-available").wrap(rate_limiter.limit("/username-available")).route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(username_available::<B>)
+                )
+            )
+            .service(
+                web::resource("/verify-email").wrap(rate_limiter.limit("/verify-email")).route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(verify_email::<B>)
+                )
+            )
+            .service(
+                web::resource("/password/forgot").wrap(rate_limiter.limit("/password/forgot")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(forgot_password::<B>)
+                )
+            )
+            .service(
+                web::resource("/password/reset").wrap(rate_limiter.limit("/password/reset")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(reset_password::<B>)
+                )
+            )
+            .service(
+                web::resource("/password/change").wrap(rate_limiter.limit("/password/change")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(change_password::<B>)
+                )
+            )
+            .service(
+                web::resource("/unlock-account").wrap(rate_limiter.limit("/unlock-account")).route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(unlock_account::<B>)
+                )
+            )
+            .service(
+                web::resource("/logout").wrap(rate_limiter.limit("/logout")).route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(logout::<B>)
                 )
             )
             .service(
-                web::resource("/sanitize").route(
+                web::resource("/logout-all").wrap(rate_limiter.limit("/logout-all")).route(
                     web::route()
                         .guard(guard::Post())
-                        .to(save_credentials)    
+                        .to(logout_all::<B>)
                 )
             )
             .service(
-                web::resource("/guest").route(
+                web::resource("/me").route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(me::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/jobs").route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(list_jobs::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/jobs/{name}/runs").route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(job_history::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/jobs/{name}/run").route(
                     web::route()
                         .guard(guard::Post())
-                        .to(guest_credentials)
+                        .to(trigger_job::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/lockouts").route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(list_lockouts::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/lockouts/unlock").route(
+                    web::route()
+                        .guard(guard::Post())
+                        .to(unlock::<B>)
+                )
+            )
+            .service(
+                web::resource("/admin/users").route(
+                    web::route()
+                        .guard(guard::Get())
+                        .to(list_users::<B>)
                 )
             )
     })
-    .bind("127.0.0.1:8081")?
-    .run()
-    .await
+    //Database work runs on these threads, never on the request threads
+    .worker_max_blocking_threads(blocking_threads);
+
+    //Certificates are only loaded when a tls listener exists
+    let tls_config = match &tls_settings{
+        Some(settings) if listeners.iter().any(|listener| listener.tls) => {
+            let store = Arc::new(CertificateStore::load(settings)?);
+            watch_certificates(store.clone());
+            Some(CertificateStore::server_config(store)?)
+        },
+        _ => None,
+    };
+
+    let mut https_port: Option<u16> = None;
+    let mut redirects: Vec<String> = vec![];
+
+    for listener in &listeners{
+        match (listener.tls, &tls_config){
+            (true, Some(tls_config)) => {
+                server = server.bind_rustls_0_23(&listener.address, tls_config.clone())?;
+                https_port = https_port.or(server.addrs().last().map(|address| address.port()));
+                println!("Listening on https://{}", listener.address);
+            },
+            _ if listener.redirect_to_https => {
+                redirects.push(listener.address.clone());
+            },
+            _ => {
+                server = server.bind(&listener.address)?;
+                println!("Listening on http://{}", listener.address);
+            },
+        }
+    }
+
+    match (redirects.is_empty(), https_port){
+        (false, Some(port)) => {
+            let redirect = web::Data::new(HttpsRedirect { port: port, host: public_host });
+            let mut redirect_server = HttpServer::new(move ||{
+                App::new()
+                    .app_data(redirect.clone())
+                    .default_service(web::to(redirect_to_https))
+            });
+
+            for address in &redirects{
+                redirect_server = redirect_server.bind(address)?;
+                println!("Redirecting http://{} to https", address);
+            }
+
+            try_join(server.run(), redirect_server.run()).await?;
+            Ok(())
+        },
+        _ => server.run().await,
+    }
 }
 
+//Reload tls certificates from disk on SIGHUP
+#[cfg(unix)]
+fn watch_certificates(store: Arc<CertificateStore>){
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        match signal(SignalKind::hangup()){
+            Ok(mut hangup) => {
+                while hangup.recv().await.is_some(){
+                    match store.reload(){
+                        Ok(_) => println!("Reloaded tls certificates..."),
+                        Err(error) => eprintln!("Error reloading tls certificates, keeping previous: {}", error),
+                    }
+                }
+            },
+            Err(error) => eprintln!("Error installing SIGHUP handler: {:?}", error),
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn watch_certificates(_store: Arc<CertificateStore>){}
+
 //Cookie dispatcher
-pub fn cookie_handler() -> SessionMiddleware<CookieSessionStore> {
+pub fn cookie_handler(settings: &CookieSettings, key: &Key) -> SessionMiddleware<CookieSessionStore> {
     SessionMiddleware::builder(
-	    CookieSessionStore::default(), Key::from(&[0; 64])
+	    CookieSessionStore::default(), key.clone()
     )
-    .cookie_name(String::from("almc-tech"))
-    .cookie_secure(true)
-    .cookie_http_only(true)
-    .cookie_same_site(actix_web::cookie::SameSite::Strict)
+    .cookie_name(settings.name.clone())
+    .cookie_secure(settings.secure)
+    .cookie_http_only(settings.http_only)
+    .cookie_same_site(settings.same_site.into())
     .cookie_content_security(CookieContentSecurity::Private)
     .session_lifecycle(BrowserSession::default())
 	.build()
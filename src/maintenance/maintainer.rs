@@ -1,35 +1,121 @@
 use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
-use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 use tokio::sync::Mutex as TokioMutex;
 
-use crate::database::handler::DatabaseHandler;
+use crate::{auth::hasher::is_legacy_salt, config::server_config::JobSettings, database::store::{Backend, GuestStore, MaintenanceStore, UserStore}, models::database_models::MaintenanceRun, utils::time::unix_now};
 
-pub struct Maintainer {
+///Maintenance task. Returns the number of rows it affected, or a description of the failure.
+pub type Task = Arc<dyn Fn() -> Result<usize, String> + Send + Sync>;
+
+///What started a job run.
+#[derive(Debug, Clone, Copy)]
+pub enum Trigger{
+    Schedule,
+    Manual,
+}
+
+impl fmt::Display for Trigger{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Trigger::Schedule => write!(f, "schedule"),
+            Trigger::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+///Reason a job could not be run.
+#[derive(Debug)]
+pub enum TriggerError{
+    UnknownJob,
+    AlreadyRunning,
+}
+
+struct RegisteredJob{
+    settings: JobSettings,
+    task: Task,
+    running: AtomicBool,
+    last_run: Mutex<Option<MaintenanceRun>>,
+}
+
+///Marks a job as running until dropped. Shared with the blocking task of an attempt,
+///so an attempt that outlives its timeout keeps the job marked until it returns.
+struct RunningGuard(Arc<RegisteredJob>);
+
+impl Drop for RunningGuard{
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+    }
+}
+
+///Job as reported to administrators.
+#[derive(Serialize)]
+pub struct JobStatus{
+    name: String,
+    schedule: String,
+    timeout_seconds: u64,
+    max_retries: u32,
+    retry_backoff_seconds: u64,
+    running: bool,
+    last_run: Option<MaintenanceRun>,
+}
+
+///Registry of named maintenance jobs, run on their cron schedule or on demand.
+///Every run is recorded in the `maintenance_runs` table.
+#[derive(Clone)]
+pub struct Maintainer<B: Backend> {
     scheduler: Arc<TokioMutex<JobScheduler>>,
+    jobs: Arc<Mutex<BTreeMap<String, Arc<RegisteredJob>>>>,
+    database: B,
 }
 
-impl Maintainer {
-    pub async fn new() -> Self {
+impl<B: Backend> Maintainer<B> {
+    pub async fn new(database: B) -> Self {
         let scheduler = JobScheduler::new();
         Maintainer {
             scheduler: Arc::new(TokioMutex::new(scheduler)),
+            jobs: Arc::new(Mutex::new(BTreeMap::new())),
+            database: database,
         }
     }
 
-    // Method to add a job to the scheduler
-    pub async fn schedule_task<F>(&self, task: F) -> Result<(), JobSchedulerError>
+    // Register a named job and add it to the scheduler, running on its cron schedule
+    pub async fn register<F>(&self, name: &str, settings: &JobSettings, task: F) -> Result<(), JobSchedulerError>
     where
-        F: Fn() -> String + Send + Sync + Clone + 'static,
+        F: Fn() -> Result<usize, String> + Send + Sync + 'static,
     {
-        //FIXME : Correct the interval
-        let job = Job::new_async("* * * * * *", move |_uuid, _l| {
-            let task = task.clone();
-            Box::pin(async move {
-                let result = task();
-                println!("Task executed at midnight: {}", result);
-            })
+        //Last recorded run survives restarts
+        let job = name.to_string();
+        let last_run = self.database.run(move |handler| Ok(handler.get_maintenance_runs(&job, 1)?)).await
+            .ok()
+            .and_then(|mut runs| runs.pop());
+
+        let registered = Arc::new(RegisteredJob {
+            settings: settings.clone(),
+            task: Arc::new(task),
+            running: AtomicBool::new(false),
+            last_run: Mutex::new(last_run),
+        });
+
+        match self.jobs.lock(){
+            Ok(mut jobs) => jobs.insert(name.to_string(), registered),
+            Err(_) => return Err(JobSchedulerError::CantAdd),
+        };
+
+        let maintainer = self.clone();
+        let job_name = name.to_string();
+        let job = Job::new_async(&settings.schedule, move |_uuid, _l| {
+            let maintainer = maintainer.clone();
+            let job_name = job_name.clone();
+
+            //Runs are spawned so a slow job never holds up the scheduler tick
+            if let Err(error) = maintainer.spawn(&job_name, Trigger::Schedule){
+                println!("Job {} skipped: {:?}", job_name, error);
+            }
+
+            Box::pin(async {})
         })
-        .unwrap();
+        .map_err(|_| JobSchedulerError::CantAdd)?;
 
         let mut scheduler = self.scheduler.lock().await;
         return scheduler.add(job);
@@ -37,17 +123,161 @@ impl Maintainer {
 
     // Start the scheduler
     pub async fn start(&self) {
-        let scheduler = self.scheduler.lock().await;
-        if let Err(e) = scheduler.start().await{
+        let handle = {
+            let scheduler = self.scheduler.lock().await;
+            scheduler.start()
+        };
+
+        if let Err(e) = handle.await{
             eprintln!("Error on scheduler {:?}", e);
         }
     }
+
+    ///Status of every registered job, ordered by name.
+    pub fn status(&self) -> Vec<JobStatus>{
+        let jobs = match self.jobs.lock(){
+            Ok(jobs) => jobs,
+            Err(_) => return vec![],
+        };
+
+        return jobs.iter().map(|(name, job)| JobStatus {
+            name: name.clone(),
+            schedule: job.settings.schedule.clone(),
+            timeout_seconds: job.settings.timeout_seconds,
+            max_retries: job.settings.max_retries,
+            retry_backoff_seconds: job.settings.retry_backoff_seconds,
+            running: job.running.load(Ordering::SeqCst),
+            last_run: job.last_run.lock().ok().and_then(|run| run.clone()),
+        }).collect()
+    }
+
+    ///Whether a job with this name is registered.
+    pub fn has_job(&self, name: &str) -> bool{
+        return self.jobs.lock().is_ok_and(|jobs| jobs.contains_key(name))
+    }
+
+    ///Start a job in the background. Fails at once when the job is unknown or already running.
+    pub fn spawn(&self, name: &str, trigger: Trigger) -> Result<(), TriggerError>{
+        let guard = self.claim(name)?;
+        let maintainer = self.clone();
+        let name = name.to_string();
+
+        tokio::spawn(async move {
+            let run = maintainer.execute(&name, guard, trigger).await;
+            println!("Job {} finished: {:?}", name, run);
+        });
+
+        return Ok(())
+    }
+
+    ///Mark a job as running, unless it already is.
+    fn claim(&self, name: &str) -> Result<Arc<RunningGuard>, TriggerError>{
+        let job = match self.jobs.lock(){
+            Ok(jobs) => jobs.get(name).cloned().ok_or(TriggerError::UnknownJob)?,
+            Err(_) => return Err(TriggerError::UnknownJob),
+        };
+
+        if job.running.swap(true, Ordering::SeqCst){
+            return Err(TriggerError::AlreadyRunning)
+        }
+
+        return Ok(Arc::new(RunningGuard(job)))
+    }
+
+    ///Run a claimed job, retrying failed attempts per its settings, and record the outcome.
+    ///A job never runs twice at the same time. An attempt that times out is not retried and keeps
+    ///the job marked as running until it returns, since blocking tasks cannot be cancelled.
+    async fn execute(&self, name: &str, guard: Arc<RunningGuard>, trigger: Trigger) -> MaintenanceRun{
+        let job = guard.0.clone();
+        let started_at = unix_now();
+        let clock = Instant::now();
+        let timeout = Duration::from_secs(job.settings.timeout_seconds);
+        let mut attempts: u32 = 0;
+
+        let outcome = loop{
+            attempts += 1;
+            let task = job.task.clone();
+            let attempt_guard = guard.clone();
+
+            let outcome = match tokio::time::timeout(timeout, tokio::task::spawn_blocking(move || {
+                let _running = attempt_guard;
+                task()
+            })).await{
+                Ok(Ok(result)) => result,
+                Ok(Err(error)) => Err(format!("task panicked: {}", error)),
+                Err(_) => break Err(format!("timed out after {} second(s)", job.settings.timeout_seconds)),
+            };
+
+            match &outcome{
+                Err(error) if attempts <= job.settings.max_retries => {
+                    let backoff = Duration::from_secs(job.settings.retry_backoff_seconds.saturating_mul(1 << (attempts - 1).min(16)));
+                    println!("Job {} attempt {} failed: {}. Retrying in {:?}...", name, attempts, error, backoff);
+                    tokio::time::sleep(backoff).await;
+                },
+                _ => break outcome,
+            }
+        };
+
+        let run = MaintenanceRun::new(
+            name.to_string(),
+            trigger.to_string(),
+            started_at,
+            clock.elapsed().as_millis() as i64,
+            outcome.is_ok(),
+            outcome.as_ref().ok().map(|rows| *rows as i64),
+            attempts as i64,
+            outcome.err()
+        );
+
+        let record = run.clone();
+        let persisted = self.database.run(move |handler| Ok(handler.insert_maintenance_run(&record)?)).await;
+
+        if let Err(error) = persisted{
+            println!("Error while recording run of job {}: {}", name, error);
+        }
+
+        if let Ok(mut last_run) = job.last_run.lock(){
+            *last_run = Some(run.clone());
+        }
+
+        return run
+    }
 }
 
 ///Guest session cleanup function.
-pub fn guest_cleanup(handler_op: Arc<Mutex<DatabaseHandler>>) -> String {
-    let handler = handler_op.lock().unwrap();
-    
-    return "".to_string()
+///Deletes guests past their expiry together with their sessions, and reports how many rows were removed.
+pub fn guest_cleanup<B: Backend>(database: B) -> Result<usize, String> {
+    match database.with(|handler| Ok(handler.delete_expired_guests(unix_now())?)){
+        Ok((guests, sessions)) => {
+            println!("Guest cleanup removed {} guest(s) and {} session(s).", guests, sessions);
+            return Ok(guests + sessions)
+        },
+        Err(error) => {
+            return Err(error.to_string())
+        },
+    }
 }
 
+///Flag accounts whose salt was derived from their credentials, so their password is rehashed at the next login.
+///Returns the number of accounts flagged by this run.
+pub fn flag_legacy_salts<B: Backend>(database: B) -> Result<usize, String> {
+    let flagged = database.with(|handler| {
+        let legacy: Vec<String> = handler.get_unflagged_salts()?
+            .into_iter()
+            .filter(|(_, salt)| is_legacy_salt(salt))
+            .map(|(user_id, _)| user_id)
+            .collect();
+
+        return Ok(handler.flag_rehash_required(&legacy)?)
+    });
+
+    match flagged{
+        Ok(flagged) => {
+            println!("Legacy salt check flagged {} account(s) for rehash.", flagged);
+            return Ok(flagged)
+        },
+        Err(error) => {
+            return Err(error.to_string())
+        },
+    }
+}
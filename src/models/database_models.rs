@@ -1,4 +1,5 @@
 use argon2::password_hash::SaltString;
+use serde::Serialize;
 use uuid::Uuid;
 
 
@@ -9,16 +10,25 @@ pub struct User{
     username: String,
     password: String,
     active_sessions: i32,
-    salt: SaltString
+    salt: SaltString,
+    email: Option<String>,
+    email_verified: bool,
+    display_username: Option<String>,
+    rehash_required: bool
 }
 impl User{
-    pub fn new(id: Uuid, username: String, password: String, active_sessions: i32, salt: SaltString) -> User{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(id: Uuid, username: String, password: String, active_sessions: i32, salt: SaltString, email: Option<String>, email_verified: bool, display_username: Option<String>, rehash_required: bool) -> User{
         User { 
             id: id, 
             username: username, 
             password: password, 
             active_sessions: active_sessions, 
-            salt: salt 
+            salt: salt,
+            email: email,
+            email_verified: email_verified,
+            display_username: display_username,
+            rehash_required: rehash_required
         }
     }
 
@@ -41,20 +51,45 @@ impl User{
     pub fn get_salt(&self) -> SaltString{
         return self.salt.clone()
     }
+
+    ///Normalized email address. Accounts created before emails were collected have none.
+    pub fn get_email(&self) -> &Option<String>{
+        return &self.email
+    }
+
+    pub fn is_email_verified(&self) -> bool{
+        return self.email_verified
+    }
+
+    ///Encrypted username for display. Absent for legacy accounts and when display names are not stored.
+    pub fn get_display_username(&self) -> &Option<String>{
+        return &self.display_username
+    }
+
+    ///Flagged for a new password hash at the next login, e.g. for a legacy salt.
+    pub fn is_rehash_required(&self) -> bool{
+        return self.rehash_required
+    }
 }
 
 #[derive(Debug)]
-///Session model.
+///Session model. Timestamps are seconds since the unix epoch.
 pub struct Session{
     id: Uuid,
     user_id: Uuid,
+    created_at: i64,
+    last_seen_at: i64,
+    expires_at: i64,
 }
 
 impl Session{
-    pub fn new(session_id: Uuid, user_id: Uuid) -> Self{
+    pub fn new(session_id: Uuid, user_id: Uuid, created_at: i64, last_seen_at: i64, expires_at: i64) -> Self{
         Self { 
             id: session_id, 
-            user_id: user_id
+            user_id: user_id,
+            created_at: created_at,
+            last_seen_at: last_seen_at,
+            expires_at: expires_at
         }
     }
 
@@ -65,4 +100,248 @@ impl Session{
     pub fn get_user_id(&self) -> &Uuid{
         return &self.user_id
     }
-}
\ No newline at end of file
+
+    pub fn get_created_at(&self) -> &i64{
+        return &self.created_at
+    }
+
+    pub fn get_last_seen_at(&self) -> &i64{
+        return &self.last_seen_at
+    }
+
+    pub fn get_expires_at(&self) -> &i64{
+        return &self.expires_at
+    }
+
+    ///Whether the session passed its absolute expiry, or has been idle for `idle_seconds`.
+    pub fn is_expired(&self, now: i64, idle_seconds: i64) -> bool{
+        return now >= self.expires_at || now - self.last_seen_at >= idle_seconds
+    }
+}
+
+#[derive(Debug)]
+///Guest model. Guests have no user row until they register.
+pub struct Guest{
+    id: Uuid,
+    session_id: Uuid,
+    created_at: i64,
+    expires_at: i64,
+}
+
+impl Guest{
+    pub fn new(id: Uuid, session_id: Uuid, created_at: i64, expires_at: i64) -> Self{
+        Self {
+            id: id,
+            session_id: session_id,
+            created_at: created_at,
+            expires_at: expires_at
+        }
+    }
+
+    pub fn get_id(&self) -> &Uuid{
+        return &self.id
+    }
+
+    pub fn get_session_id(&self) -> &Uuid{
+        return &self.session_id
+    }
+
+    pub fn get_created_at(&self) -> &i64{
+        return &self.created_at
+    }
+
+    pub fn get_expires_at(&self) -> &i64{
+        return &self.expires_at
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+///Maintenance job run model. `rows_affected` is set on success, `error` on failure.
+pub struct MaintenanceRun{
+    job: String,
+    trigger: String,
+    started_at: i64,
+    duration_ms: i64,
+    success: bool,
+    rows_affected: Option<i64>,
+    attempts: i64,
+    error: Option<String>,
+}
+
+impl MaintenanceRun{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(job: String, trigger: String, started_at: i64, duration_ms: i64, success: bool, rows_affected: Option<i64>, attempts: i64, error: Option<String>) -> Self{
+        Self {
+            job: job,
+            trigger: trigger,
+            started_at: started_at,
+            duration_ms: duration_ms,
+            success: success,
+            rows_affected: rows_affected,
+            attempts: attempts,
+            error: error
+        }
+    }
+
+    pub fn get_job(&self) -> &String{
+        return &self.job
+    }
+
+    pub fn get_trigger(&self) -> &String{
+        return &self.trigger
+    }
+
+    pub fn get_started_at(&self) -> &i64{
+        return &self.started_at
+    }
+
+    pub fn get_duration_ms(&self) -> &i64{
+        return &self.duration_ms
+    }
+
+    pub fn get_success(&self) -> &bool{
+        return &self.success
+    }
+
+    pub fn get_rows_affected(&self) -> &Option<i64>{
+        return &self.rows_affected
+    }
+
+    pub fn get_attempts(&self) -> &i64{
+        return &self.attempts
+    }
+
+    pub fn get_error(&self) -> &Option<String>{
+        return &self.error
+    }
+}
+
+#[derive(Debug, Clone)]
+///Queued outgoing mail.
+pub struct OutboxMail{
+    id: i64,
+    recipient: String,
+    subject: String,
+    body: String,
+    attempts: i64,
+}
+
+impl OutboxMail{
+    pub fn new(id: i64, recipient: String, subject: String, body: String, attempts: i64) -> Self{
+        Self {
+            id: id,
+            recipient: recipient,
+            subject: subject,
+            body: body,
+            attempts: attempts
+        }
+    }
+
+    pub fn get_id(&self) -> &i64{
+        return &self.id
+    }
+
+    pub fn get_recipient(&self) -> &String{
+        return &self.recipient
+    }
+
+    pub fn get_subject(&self) -> &String{
+        return &self.subject
+    }
+
+    pub fn get_body(&self) -> &String{
+        return &self.body
+    }
+
+    pub fn get_attempts(&self) -> &i64{
+        return &self.attempts
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+///Failed login counter. `scope` is "account" with the username hash as key, or "ip" with the client address.
+pub struct LoginAttempt{
+    scope: String,
+    key: String,
+    failures: i64,
+    last_failure_at: i64,
+    locked_until: i64,
+}
+
+impl LoginAttempt{
+    pub fn new(scope: String, key: String, failures: i64, last_failure_at: i64, locked_until: i64) -> Self{
+        Self {
+            scope: scope,
+            key: key,
+            failures: failures,
+            last_failure_at: last_failure_at,
+            locked_until: locked_until
+        }
+    }
+
+    pub fn get_failures(&self) -> &i64{
+        return &self.failures
+    }
+
+    pub fn get_last_failure_at(&self) -> &i64{
+        return &self.last_failure_at
+    }
+
+    pub fn get_locked_until(&self) -> &i64{
+        return &self.locked_until
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+///Username hash registered by more than one user.
+pub struct DuplicateUsername{
+    username: String,
+    user_ids: Vec<String>,
+}
+
+impl DuplicateUsername{
+    pub fn new(username: String, user_ids: Vec<String>) -> Self{
+        Self {
+            username: username,
+            user_ids: user_ids
+        }
+    }
+
+    pub fn get_username(&self) -> &String{
+        return &self.username
+    }
+
+    pub fn get_user_ids(&self) -> &Vec<String>{
+        return &self.user_ids
+    }
+}
+
+#[derive(Debug, Clone)]
+///Schema migration recorded in `schema_migrations`.
+pub struct AppliedMigration{
+    version: u32,
+    description: String,
+    applied_at: i64,
+}
+
+impl AppliedMigration{
+    pub fn new(version: u32, description: String, applied_at: i64) -> Self{
+        Self {
+            version: version,
+            description: description,
+            applied_at: applied_at
+        }
+    }
+
+    pub fn get_version(&self) -> &u32{
+        return &self.version
+    }
+
+    pub fn get_description(&self) -> &String{
+        return &self.description
+    }
+
+    pub fn get_applied_at(&self) -> &i64{
+        return &self.applied_at
+    }
+}
@@ -10,5 +10,46 @@ pub struct MessageBody {
 #[derive(Deserialize, Debug, Clone)]
 pub struct Credentials {
     pub username: String,
+    pub password: String,
+    ///Required on registration, ignored on login.
+    #[serde(default)]
+    pub email: Option<String>
+}
+
+///Password reset request sent from client side.
+#[derive(Deserialize, Debug)]
+pub struct ForgotPasswordBody {
+    pub data: ForgotPassword
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForgotPassword {
+    pub email: String
+}
+
+///New password with the token from a reset link.
+#[derive(Deserialize, Debug)]
+pub struct ResetPasswordBody {
+    pub data: ResetPassword
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetPassword {
+    pub token: String,
     pub password: String
+}
+
+///Password change sent by a logged in user.
+#[derive(Deserialize, Debug)]
+pub struct ChangePasswordBody {
+    pub data: ChangePassword
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChangePassword {
+    pub old_password: String,
+    pub new_password: String,
+    ///Log out every other session of the user.
+    #[serde(default)]
+    pub revoke_other_sessions: bool
 }
\ No newline at end of file
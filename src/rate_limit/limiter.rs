@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, rc::Rc, sync::Arc};
+
+use actix_web::{body::{EitherBody, MessageBody}, dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, web, Error, ResponseError};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::{auth::errors::AuthError, config::server_config::{BucketSettings, RateLimitSettings, RateLimitStoreKind}, database::store::Backend, utils::{client_ip::TrustedProxies, time::unix_now_precise}};
+
+use super::store::{BucketStore, Decision, DatabaseStore, MemoryStore};
+
+
+///Token bucket limits of every configured route, and the store their buckets are kept in.
+pub struct RateLimiter{
+    enabled: bool,
+    routes: BTreeMap<String, BucketSettings>,
+    proxies: TrustedProxies,
+    store: Arc<dyn BucketStore>,
+}
+
+impl RateLimiter{
+    ///Limiter with the configured store. The sqlite store keeps its buckets in the database backend.
+    pub fn new<B: Backend>(settings: &RateLimitSettings, proxies: TrustedProxies, database: &B) -> Self{
+        let store: Arc<dyn BucketStore> = match settings.store{
+            RateLimitStoreKind::Memory => Arc::new(MemoryStore::default()),
+            RateLimitStoreKind::Sqlite => {
+                //Longest time any bucket takes to fill up again
+                let idle_seconds = settings.routes.values()
+                    .map(|bucket| bucket.capacity as f64 * 60.0 / bucket.refill_per_minute as f64)
+                    .fold(0.0, f64::max);
+
+                Arc::new(DatabaseStore::new(database.clone(), idle_seconds))
+            },
+        };
+
+        return RateLimiter {
+            enabled: settings.enabled,
+            routes: settings.routes.clone(),
+            proxies: proxies,
+            store: store
+        }
+    }
+
+    ///Middleware limiting the resource or scope it wraps with the bucket configured under `route` in `rate_limit.routes`.
+    ///The bucket is resolved once here. Without one, or with rate limiting disabled, requests pass untouched.
+    pub fn limit(self: &Arc<Self>, route: &str) -> RateLimit{
+        let bucket = match self.enabled{
+            true => self.routes.get(route).copied(),
+            false => None,
+        };
+
+        return RateLimit {
+            limiter: self.clone(),
+            route: Rc::from(route),
+            bucket: bucket
+        }
+    }
+
+    ///Take a token from the bucket of `client` on `route`.
+    ///Store failures let the request through, a broken limiter must not take the service down.
+    async fn check(&self, route: &str, bucket: BucketSettings, client: &str) -> Decision{
+        let key = format!("{} {}", route, client);
+        let now = unix_now_precise();
+        let taken = match self.store.is_blocking(){
+            true => {
+                let store = self.store.clone();
+                web::block(move || store.take(&key, &bucket, now)).await
+                    .unwrap_or_else(|error| Err(error.to_string()))
+            },
+            false => self.store.take(&key, &bucket, now),
+        };
+
+        match taken{
+            Ok(decision) => return decision,
+            Err(error) => {
+                println!("Error while rate limiting {}: {}", route, error);
+                return Decision::Allowed
+            },
+        }
+    }
+}
+
+
+///Limit of one resource or scope, made by `RateLimiter::limit`. Limited requests get 429 with Retry-After.
+#[derive(Clone)]
+pub struct RateLimit{
+    limiter: Arc<RateLimiter>,
+    route: Rc<str>,
+    bucket: Option<BucketSettings>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), limit: self.clone() }))
+    }
+}
+
+pub struct RateLimitMiddleware<S>{
+    service: Rc<S>,
+    limit: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limit = self.limit.clone();
+
+        Box::pin(async move {
+            let bucket = match limit.bucket{
+                Some(bucket) => bucket,
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+
+            let client = match limit.limiter.proxies.client_ip(req.peer_addr(), req.headers()){
+                Some(address) => address.to_string(),
+                None => String::from("unknown"),
+            };
+
+            match limit.limiter.check(&limit.route, bucket, &client).await{
+                Decision::Allowed => {
+                    return Ok(service.call(req).await?.map_into_left_body())
+                },
+                Decision::Limited(seconds) => {
+                    let response = AuthError::RateLimited(seconds).error_response();
+                    return Ok(req.into_response(response).map_into_right_body())
+                },
+            }
+        })
+    }
+}
@@ -0,0 +1,4 @@
+pub mod limiter;
+pub mod store;
+#[cfg(test)]
+mod tests;
\ No newline at end of file
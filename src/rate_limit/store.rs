@@ -0,0 +1,153 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use crate::{config::server_config::BucketSettings, database::store::{Backend, RateLimitStore}};
+
+///Buckets kept in memory before the least recently used ones are dropped.
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+///Buckets dropped at once when the limit is reached, so the scan is not repeated on every new client.
+const EVICTED_BUCKETS: usize = MAX_MEMORY_BUCKETS / 10;
+///Takes between two sweeps of idle buckets in the database.
+const SWEEP_INTERVAL: u64 = 1_000;
+
+
+///Outcome of taking a token.
+#[derive(Debug, Clone, Copy)]
+pub enum Decision{
+    Allowed,
+    ///No token left. Holds the seconds until the next one.
+    Limited(u64),
+}
+
+///Storage of token buckets.
+pub trait BucketStore: Send + Sync{
+    ///Take one token from the bucket under `key`, creating a full bucket when there is none.
+    fn take(&self, key: &str, bucket: &BucketSettings, now: f64) -> Result<Decision, String>;
+
+    ///Whether taking a token does disk I/O and has to run on the blocking thread pool.
+    fn is_blocking(&self) -> bool{
+        return false
+    }
+}
+
+///Tokens left in a bucket and when they were counted.
+///The one implementation of the bucket arithmetic for buckets kept in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket{
+    tokens: f64,
+    updated_at: f64,
+}
+
+impl TokenBucket{
+    pub fn full(capacity: f64, now: f64) -> Self{
+        TokenBucket { tokens: capacity, updated_at: now }
+    }
+
+    ///Refill for the time since the last take, then take a token if one is left. Returns whether a token was taken.
+    pub fn take(&mut self, capacity: f64, refill_per_second: f64, now: f64) -> bool{
+        let available = self.available(capacity, refill_per_second, now);
+        let allowed = available >= 1.0;
+
+        self.tokens = if allowed { available - 1.0 } else { available };
+        self.updated_at = now;
+        return allowed
+    }
+
+    pub fn get_tokens(&self) -> f64{
+        return self.tokens
+    }
+
+    pub fn get_updated_at(&self) -> f64{
+        return self.updated_at
+    }
+
+    fn available(&self, capacity: f64, refill_per_second: f64, now: f64) -> f64{
+        return (self.tokens + (now - self.updated_at) * refill_per_second).min(capacity)
+    }
+}
+
+fn refill_per_second(bucket: &BucketSettings) -> f64{
+    return bucket.refill_per_minute as f64 / 60.0
+}
+
+///Decision for a bucket left with `tokens` after a take.
+fn decide(allowed: bool, tokens: f64, bucket: &BucketSettings) -> Decision{
+    if allowed{
+        return Decision::Allowed
+    }
+
+    let wait = ((1.0 - tokens) / refill_per_second(bucket)).ceil().max(1.0);
+    return Decision::Limited(wait as u64)
+}
+
+
+///Buckets of this instance only.
+#[derive(Default)]
+pub struct MemoryStore{
+    buckets: Mutex<HashMap<String, TokenBucket>>
+}
+
+impl BucketStore for MemoryStore{
+    fn take(&self, key: &str, bucket: &BucketSettings, now: f64) -> Result<Decision, String> {
+        let mut buckets = self.buckets.lock().map_err(|error| error.to_string())?;
+        let capacity = bucket.capacity as f64;
+        let rate = refill_per_second(bucket);
+
+        if buckets.len() >= MAX_MEMORY_BUCKETS && !buckets.contains_key(key){
+            evict_oldest(&mut buckets);
+        }
+
+        let stored = buckets.entry(key.to_string()).or_insert(TokenBucket::full(capacity, now));
+        let allowed = stored.take(capacity, rate, now);
+
+        return Ok(decide(allowed, stored.get_tokens(), bucket))
+    }
+}
+
+///Drop the least recently used buckets. Buckets of other routes may have other capacities,
+///so the age is the only thing they can be compared by.
+fn evict_oldest(buckets: &mut HashMap<String, TokenBucket>){
+    let mut ages: Vec<f64> = buckets.values().map(|stored| stored.get_updated_at()).collect();
+    let (_, cutoff, _) = ages.select_nth_unstable_by(EVICTED_BUCKETS - 1, f64::total_cmp);
+    let cutoff = *cutoff;
+
+    buckets.retain(|_, stored| stored.get_updated_at() > cutoff);
+}
+
+
+///Buckets in the database backend, shared by every instance using it.
+pub struct DatabaseStore<B: Backend>{
+    database: B,
+    idle_seconds: f64,
+    takes: AtomicU64,
+}
+
+impl<B: Backend> DatabaseStore<B>{
+    ///Store on the shared backend. Buckets unused for `idle_seconds` are swept now and then.
+    pub fn new(database: B, idle_seconds: f64) -> Self{
+        DatabaseStore {
+            database: database,
+            idle_seconds: idle_seconds,
+            takes: AtomicU64::new(0)
+        }
+    }
+}
+
+impl<B: Backend> BucketStore for DatabaseStore<B>{
+    fn is_blocking(&self) -> bool{
+        return true
+    }
+
+    fn take(&self, key: &str, bucket: &BucketSettings, now: f64) -> Result<Decision, String> {
+        let sweep = self.takes.fetch_add(1, Ordering::Relaxed).is_multiple_of(SWEEP_INTERVAL);
+
+        let (allowed, tokens) = self.database.with(|handler| {
+            if sweep{
+                handler.delete_idle_rate_limit_buckets(now - self.idle_seconds)?;
+            }
+
+            return Ok(handler.take_rate_limit_token(key, bucket.capacity as f64, refill_per_second(bucket), now)?)
+        }).map_err(|error| error.to_string())?;
+
+        return Ok(decide(allowed, tokens, bucket))
+    }
+}
@@ -0,0 +1,69 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+use crate::{config::server_config::{BucketSettings, RateLimitSettings}, database::memory::MemoryDatabase, utils::client_ip::TrustedProxies};
+
+use super::{limiter::RateLimiter, store::{BucketStore, Decision, MemoryStore}};
+
+
+fn limiter(routes: &[(&str, u32)]) -> Arc<RateLimiter>{
+    let settings = RateLimitSettings {
+        routes: routes.iter()
+            .map(|(route, capacity)| (route.to_string(), BucketSettings { capacity: *capacity, refill_per_minute: 1 }))
+            .collect::<BTreeMap<_, _>>(),
+        ..RateLimitSettings::default()
+    };
+
+    return Arc::new(RateLimiter::new(&settings, TrustedProxies::default(), &MemoryDatabase::default()))
+}
+
+fn get(path: &str, peer: &str) -> test::TestRequest{
+    return test::TestRequest::get().uri(path).peer_addr(peer.parse().unwrap())
+}
+
+
+#[actix_web::test]
+async fn each_resource_has_its_own_bucket(){
+    let limiter = limiter(&[("/a", 1), ("/b", 2)]);
+    let app = test::init_service(
+        App::new()
+            .service(web::resource("/a").wrap(limiter.limit("/a")).to(HttpResponse::Ok))
+            .service(web::resource("/b").wrap(limiter.limit("/b")).to(HttpResponse::Ok))
+            .service(web::resource("/c").wrap(limiter.limit("/c")).to(HttpResponse::Ok))
+    ).await;
+
+    assert_eq!(test::call_service(&app, get("/a", "10.0.0.1:1000").to_request()).await.status(), StatusCode::OK);
+    let response = test::call_service(&app, get("/a", "10.0.0.1:1000").to_request()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+
+    //Other clients and other resources keep their tokens
+    assert_eq!(test::call_service(&app, get("/a", "10.0.0.2:1000").to_request()).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, get("/b", "10.0.0.1:1000").to_request()).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, get("/b", "10.0.0.1:1000").to_request()).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, get("/b", "10.0.0.1:1000").to_request()).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    //Resources without a configured bucket are not limited
+    for _ in 0..5{
+        assert_eq!(test::call_service(&app, get("/c", "10.0.0.1:1000").to_request()).await.status(), StatusCode::OK);
+    }
+}
+
+#[actix_web::test]
+async fn full_memory_store_drops_the_oldest_buckets(){
+    let store = MemoryStore::default();
+    let bucket = BucketSettings { capacity: 1, refill_per_minute: 1 };
+
+    //An emptied bucket, then enough clients to fill the store
+    assert!(matches!(store.take("attacker", &bucket, 0.0), Ok(Decision::Allowed)));
+    assert!(matches!(store.take("attacker", &bucket, 0.0), Ok(Decision::Limited(_))));
+
+    for client in 0..100_000{
+        store.take(&client.to_string(), &bucket, 1.0 + client as f64 / 1_000_000.0).unwrap();
+    }
+
+    //The emptied bucket was the least recently used, yet the most recent ones are still counted
+    assert!(matches!(store.take("attacker", &bucket, 1.5), Ok(Decision::Allowed)));
+    assert!(matches!(store.take("99999", &bucket, 1.5), Ok(Decision::Limited(_))));
+}
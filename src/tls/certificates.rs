@@ -0,0 +1,99 @@
+use std::{fs::File, io::{self, BufReader}, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+
+use rustls::{crypto::ring::{default_provider, sign::any_supported_type}, pki_types::{CertificateDer, PrivateKeyDer}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ServerConfig};
+
+use crate::config::server_config::TlsSettings;
+
+
+///Certificate chain and private key served to every TLS handshake.
+///The pair can be swapped at runtime; connections already established keep their session.
+#[derive(Debug)]
+pub struct CertificateStore{
+    certificate_path: PathBuf,
+    private_key_path: PathBuf,
+    certified: RwLock<Arc<CertifiedKey>>
+}
+
+impl CertificateStore{
+    ///Load the certificate chain and private key named by the tls settings.
+    pub fn load(settings: &TlsSettings) -> io::Result<CertificateStore>{
+        let certificate_path = PathBuf::from(&settings.certificate);
+        let private_key_path = PathBuf::from(&settings.private_key);
+        let certified = read_certified_key(&certificate_path, &private_key_path)?;
+
+        return Ok(CertificateStore {
+            certificate_path: certificate_path,
+            private_key_path: private_key_path,
+            certified: RwLock::new(Arc::new(certified))
+        })
+    }
+
+    ///Read the files again and serve the new pair to future handshakes.
+    ///On failure the previous pair stays in use.
+    pub fn reload(&self) -> io::Result<()>{
+        let certified = read_certified_key(&self.certificate_path, &self.private_key_path)?;
+
+        match self.certified.write(){
+            Ok(mut current) => {
+                *current = Arc::new(certified);
+                return Ok(())
+            },
+            Err(_) => {
+                return Err(io::Error::other("certificate store lock poisoned"))
+            },
+        }
+    }
+
+    ///Rustls server configuration resolving certificates through the store.
+    pub fn server_config(store: Arc<CertificateStore>) -> io::Result<ServerConfig>{
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(store);
+
+        return Ok(config)
+    }
+}
+
+impl ResolvesServerCert for CertificateStore{
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        return self.certified.read().ok().map(|certified| certified.clone())
+    }
+}
+
+
+///Read a PEM certificate chain and private key and check that the key is usable.
+fn read_certified_key(certificate_path: &Path, private_key_path: &Path) -> io::Result<CertifiedKey>{
+    let chain = read_certificates(certificate_path)?;
+    let key = read_private_key(private_key_path)?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", private_key_path.display(), error)))?;
+
+    return Ok(CertifiedKey::new(chain, signing_key))
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>>{
+    let mut reader = BufReader::new(open(path)?);
+    let chain = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if chain.is_empty(){
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no certificates found", path.display())))
+    }
+
+    return Ok(chain)
+}
+
+fn read_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>>{
+    let mut reader = BufReader::new(open(path)?);
+
+    match rustls_pemfile::private_key(&mut reader)?{
+        Some(key) => return Ok(key),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no private key found", path.display()))),
+    }
+}
+
+fn open(path: &Path) -> io::Result<File>{
+    return File::open(path)
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+}
@@ -0,0 +1,2 @@
+pub mod certificates;
+pub mod redirect;
\ No newline at end of file
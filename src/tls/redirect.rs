@@ -0,0 +1,44 @@
+use actix_web::{http::{header::{HOST, LOCATION}, uri::Authority}, web, HttpRequest, HttpResponse, Responder};
+
+
+///Target of plain http requests: the port of the https listener, on `server.public_host` when configured.
+#[derive(Clone)]
+pub struct HttpsRedirect{
+    pub port: u16,
+    pub host: Option<String>,
+}
+
+///Handler that permanently redirects any request to the same path over https.
+pub async fn redirect_to_https(req: HttpRequest, redirect: web::Data<HttpsRedirect>) -> impl Responder {
+    let host = match &redirect.host{
+        Some(host) => host.clone(),
+        None => match request_host(&req){
+            Some(host) => host,
+            None => return HttpResponse::BadRequest().finish(),
+        },
+    };
+
+    let authority = match redirect.port{
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+
+    let target = format!("https://{}{}", authority, req.uri().path_and_query().map_or("/", |path| path.as_str()));
+
+    return HttpResponse::MovedPermanently()
+        .insert_header((LOCATION, target))
+        .finish()
+}
+
+///Host named by the request's own Host header, without its port.
+///X-Forwarded-Host and Forwarded are ignored, any client can set them. None when the header is missing or not a plain host.
+fn request_host(req: &HttpRequest) -> Option<String>{
+    let header = req.headers().get(HOST)?.to_str().ok()?;
+
+    if header.contains('@'){
+        return None
+    }
+
+    let authority = header.parse::<Authority>().ok()?;
+    return Some(authority.host().to_string())
+}
@@ -0,0 +1,108 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::HeaderMap;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+
+///Address range in CIDR notation. A plain address is a range of one.
+#[derive(Debug, Clone, Copy)]
+struct IpRange{
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange{
+    fn parse(range: &str) -> Option<Self>{
+        let (address, prefix) = match range.trim().split_once('/'){
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (range.trim(), None),
+        };
+
+        let network: IpAddr = address.parse().ok()?;
+        let bits = match network{
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix = match prefix{
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+
+        return Some(IpRange { network: network, prefix: prefix })
+    }
+
+    fn contains(&self, address: &IpAddr) -> bool{
+        match (self.network, address){
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                return u32::from(network) & mask == u32::from(*address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                return u128::from(network) & mask == u128::from(*address) & mask
+            },
+            _ => return false,
+        }
+    }
+}
+
+
+///Reverse proxies whose `X-Forwarded-For` header is believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies{
+    ranges: Vec<IpRange>
+}
+
+impl TrustedProxies{
+    ///Parse addresses and CIDR ranges. Returns the first entry that is neither.
+    pub fn parse(entries: &[String]) -> Result<Self, String>{
+        let mut ranges = vec![];
+
+        for entry in entries{
+            match IpRange::parse(entry){
+                Some(range) => ranges.push(range),
+                None => return Err(entry.clone()),
+            }
+        }
+
+        return Ok(TrustedProxies { ranges: ranges })
+    }
+
+    fn trusts(&self, address: &IpAddr) -> bool{
+        return self.ranges.iter().any(|range| range.contains(address))
+    }
+
+    ///Address of the client behind a request.
+    ///Without a trusted peer this is the peer itself. Otherwise `X-Forwarded-For` is read from the right,
+    ///skipping trusted proxies, so a client cannot spoof its address by sending the header itself.
+    pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr>{
+        let mut client = peer?.ip();
+
+        if !self.trusts(&client){
+            return Some(client)
+        }
+
+        let forwarded: Vec<&str> = headers.get_all(X_FORWARDED_FOR)
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(','))
+            .collect();
+
+        for hop in forwarded.iter().rev(){
+            match hop.trim().parse::<IpAddr>(){
+                Ok(address) => {
+                    client = address;
+
+                    if !self.trusts(&address){
+                        break;
+                    }
+                },
+                //A malformed hop ends the chain, the last trusted hop is used
+                Err(_) => break,
+            }
+        }
+
+        return Some(client)
+    }
+}
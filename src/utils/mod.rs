@@ -0,0 +1,7 @@
+pub mod client_ip;
+pub mod payload;
+pub mod request_id;
+pub mod time;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,58 @@
+use std::ops::Deref;
+
+use actix_web::{dev::Payload, web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{auth::errors::AuthError, models::server_models::{Credentials, MessageBody}};
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+
+///Json bodies up to `limit` bytes. Failures are answered with the error envelope.
+pub fn json_config(limit: usize) -> web::JsonConfig{
+    return web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|error, _req| AuthError::from(error).into())
+}
+
+///Form bodies up to `limit` bytes. Failures are answered with the error envelope.
+pub fn form_config(limit: usize) -> web::FormConfig{
+    return web::FormConfig::default()
+        .limit(limit)
+        .error_handler(|error, _req| AuthError::from(error).into())
+}
+
+///Raw bodies up to `limit` bytes.
+pub fn payload_config(limit: usize) -> web::PayloadConfig{
+    return web::PayloadConfig::new(limit)
+}
+
+
+///Credentials posted as json (`{"data": {...}}`) or from a plain html form, with the same fields at the top level.
+pub struct CredentialsPayload(MessageBody);
+
+impl Deref for CredentialsPayload{
+    type Target = MessageBody;
+
+    fn deref(&self) -> &MessageBody {
+        &self.0
+    }
+}
+
+impl FromRequest for CredentialsPayload{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if req.content_type().eq_ignore_ascii_case(FORM_CONTENT_TYPE){
+            let form = web::Form::<Credentials>::from_request(req, payload);
+            return Box::pin(async move {
+                let credentials = form.await?.into_inner();
+                Ok(CredentialsPayload(MessageBody { data: credentials }))
+            })
+        }
+
+        let json = web::Json::<MessageBody>::from_request(req, payload);
+        return Box::pin(async move { Ok(CredentialsPayload(json.await?.into_inner())) })
+    }
+}
@@ -0,0 +1,30 @@
+use actix_web::{body::MessageBody, dev::{ServiceRequest, ServiceResponse}, http::header::{HeaderName, HeaderValue}, middleware::Next, Error};
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local!{
+    static REQUEST_ID: String;
+}
+
+
+///Middleware giving every request a fresh id, sent back in the `X-Request-Id` header.
+///The id is set while the request is handled, so error bodies can carry it without access to the request.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = Uuid::new_v4().to_string();
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&id){
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    return Ok(res)
+}
+
+///Id of the request being handled, empty outside of a request.
+pub fn current_request_id() -> String{
+    return REQUEST_ID.try_with(|id| id.clone()).unwrap_or_default()
+}
@@ -0,0 +1,118 @@
+use actix_web::{http::{header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE}, StatusCode}, test, web, App, HttpResponse};
+use serde_json::{json, Value};
+
+use super::{client_ip::TrustedProxies, payload::{form_config, json_config, CredentialsPayload}};
+
+
+fn proxies(entries: &[&str]) -> TrustedProxies{
+    return TrustedProxies::parse(&entries.iter().map(|entry| entry.to_string()).collect::<Vec<String>>()).expect("proxies")
+}
+
+fn forwarded_for(hops: &[&str]) -> HeaderMap{
+    let mut headers = HeaderMap::new();
+
+    for hop in hops{
+        headers.append(HeaderName::from_static("x-forwarded-for"), HeaderValue::from_str(hop).expect("header"));
+    }
+
+    return headers
+}
+
+fn client_ip(proxies: &TrustedProxies, peer: &str, hops: &[&str]) -> Option<String>{
+    return proxies.client_ip(Some(peer.parse().expect("peer")), &forwarded_for(hops)).map(|address| address.to_string())
+}
+
+///Echo the parsed credentials.
+async fn echo_credentials(credentials: CredentialsPayload) -> HttpResponse{
+    return HttpResponse::Ok().json(json!({ "username": credentials.data.username, "email": credentials.data.email }))
+}
+
+
+#[actix_web::test]
+async fn untrusted_peers_cannot_spoof_their_address(){
+    let none = TrustedProxies::default();
+    assert_eq!(client_ip(&none, "203.0.113.7:4000", &["198.51.100.1"]).as_deref(), Some("203.0.113.7"));
+
+    let trusted = proxies(&["10.0.0.0/8"]);
+    assert_eq!(client_ip(&trusted, "203.0.113.7:4000", &["198.51.100.1"]).as_deref(), Some("203.0.113.7"));
+
+    assert_eq!(none.client_ip(None, &HeaderMap::new()), None);
+}
+
+#[actix_web::test]
+async fn forwarded_for_is_read_from_the_right(){
+    let trusted = proxies(&["10.0.0.0/8", "2001:db8::1"]);
+
+    //The client prepended a fake hop, the first untrusted hop from the right is the client
+    assert_eq!(client_ip(&trusted, "10.0.0.2:4000", &["1.2.3.4, 198.51.100.1, 10.0.0.1"]).as_deref(), Some("198.51.100.1"));
+    assert_eq!(client_ip(&trusted, "10.0.0.2:4000", &["1.2.3.4", "198.51.100.1"]).as_deref(), Some("198.51.100.1"));
+    assert_eq!(client_ip(&trusted, "[2001:db8::1]:4000", &["2001:db8::7"]).as_deref(), Some("2001:db8::7"));
+
+    //Without the header, or with only trusted hops, the last trusted address is used
+    assert_eq!(client_ip(&trusted, "10.0.0.2:4000", &[]).as_deref(), Some("10.0.0.2"));
+    assert_eq!(client_ip(&trusted, "10.0.0.2:4000", &["10.0.0.3"]).as_deref(), Some("10.0.0.3"));
+
+    //A malformed hop ends the chain
+    assert_eq!(client_ip(&trusted, "10.0.0.2:4000", &["198.51.100.1, garbage, 10.0.0.1"]).as_deref(), Some("10.0.0.1"));
+}
+
+#[actix_web::test]
+async fn proxy_ranges_must_parse(){
+    assert!(TrustedProxies::parse(&[String::from("10.0.0.0/33")]).is_err());
+    assert_eq!(TrustedProxies::parse(&[String::from("127.0.0.1"), String::from("proxy")]).err().as_deref(), Some("proxy"));
+
+    let trusted = proxies(&["192.168.1.0/24"]);
+    assert_eq!(client_ip(&trusted, "192.168.1.200:4000", &["198.51.100.1"]).as_deref(), Some("198.51.100.1"));
+    assert_eq!(client_ip(&trusted, "192.168.2.1:4000", &["198.51.100.1"]).as_deref(), Some("192.168.2.1"));
+    //Ranges of one family never hold addresses of the other
+    assert_eq!(client_ip(&trusted, "[::ffff:192.168.1.1]:4000", &["198.51.100.1"]).as_deref(), Some("::ffff:192.168.1.1"));
+}
+
+#[actix_web::test]
+async fn credentials_parse_from_json_or_form(){
+    let app = test::init_service(
+        App::new()
+            .app_data(json_config(256))
+            .app_data(form_config(256))
+            .route("/", web::post().to(echo_credentials))
+    ).await;
+
+    let request = test::TestRequest::post().uri("/").set_json(json!({ "data": { "username": "alice", "password": "secret", "email": "alice@example.com" } }));
+    let body: Value = test::call_and_read_body_json(&app, request.to_request()).await;
+    assert_eq!(body, json!({ "username": "alice", "email": "alice@example.com" }));
+
+    //Form fields sit at the top level, email is optional
+    let request = test::TestRequest::post().uri("/").set_form([("username", "bob"), ("password", "secret")]);
+    let body: Value = test::call_and_read_body_json(&app, request.to_request()).await;
+    assert_eq!(body, json!({ "username": "bob", "email": null }));
+}
+
+#[actix_web::test]
+async fn malformed_credentials_get_the_error_envelope(){
+    let app = test::init_service(
+        App::new()
+            .app_data(json_config(256))
+            .app_data(form_config(256))
+            .route("/", web::post().to(echo_credentials))
+    ).await;
+    let post = |content_type: &str, body: &str| test::TestRequest::post().uri("/")
+        .insert_header((CONTENT_TYPE, content_type.to_string()))
+        .set_payload(body.to_string())
+        .to_request();
+
+    let cases = [
+        (post("application/json", "{\"data\": {\"username\": \"alice\"}}"), StatusCode::BAD_REQUEST, "invalid_body"),
+        (post("application/json", "{\"data\": "), StatusCode::BAD_REQUEST, "invalid_body"),
+        (post("application/x-www-form-urlencoded", "username=alice"), StatusCode::BAD_REQUEST, "invalid_body"),
+        (post("application/json", &format!("{{\"data\": {{\"username\": \"{}\"}}}}", "a".repeat(300))), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+        (post("text/plain", "username=alice&password=secret"), StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type"),
+    ];
+
+    for (request, status, code) in cases{
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), status);
+
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], code);
+    }
+}
@@ -0,0 +1,20 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+
+///Current time as seconds since the unix epoch.
+pub fn unix_now() -> i64{
+    match SystemTime::now().duration_since(UNIX_EPOCH){
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+///Current time as fractional seconds since the unix epoch.
+pub fn unix_now_precise() -> f64{
+    match SystemTime::now().duration_since(UNIX_EPOCH){
+        Ok(elapsed) => elapsed.as_secs_f64(),
+        Err(_) => 0.0,
+    }
+}